@@ -4,22 +4,39 @@ use rensen_lib::traits::{YamlFile, JsonFile, Rsync};
 use rensen_lib::backup::rsync::Sftp;
 use rensen_lib::record::Record;
 use rensen_lib::compiler::Compiler;
+use rensen_lib::snapshot::DeletedEntry;
+use rensen_lib::known_hosts::*;
+use rensen_lib::crypto;
+use rensen_lib::prune::prune;
+use rensen_lib::synthesize::synthesize_full;
+use rensen_lib::restore::{restore, restore_file, resolve_snapshot, record_path, RestoreOptions, RestoreAction, ConflictPolicy};
 
 use console::Style;
 
 use crate::utils::*;
 use std::path::PathBuf; use std::fs;
+use std::io::IsTerminal;
+use std::collections::BTreeMap;
 
 #[derive(PartialEq)]
 pub enum ViewSubject {
     Snapshots,
     Config,
+    Deletions,
+}
+
+#[derive(PartialEq)]
+pub enum KeyCommand {
+    Init,
+    Rotate,
+    Migrate,
 }
 
 #[derive(PartialEq)]
 enum BackupMethod {
     Full,
-    Incremental
+    Incremental,
+    Differential,
 }
 
 #[derive(PartialEq)]
@@ -31,6 +48,11 @@ pub enum ActionType {
     Compile,    // 1 arg
     ListHosts,  // 2 arg
     View,       // 2 arg
+    Key,        // 1 arg
+    Prune,      // 1 arg
+    Synthesize, // 1 arg
+    Restore,    // 1 arg
+    Extract,    // 3 arg
 
     Clear,      // 0 arg
     Help,       // 0 arg
@@ -68,6 +90,21 @@ impl Action {
             ActionType::View       => {
                 self.view()?;
             }
+            ActionType::Key        => {
+                self.key()?;
+            }
+            ActionType::Prune      => {
+                self.prune()?;
+            }
+            ActionType::Synthesize => {
+                self.synthesize()?;
+            }
+            ActionType::Restore    => {
+                self.restore()?;
+            }
+            ActionType::Extract    => {
+                self.extract()?;
+            }
             ActionType::Help       => {
                 self.print_help();
             }
@@ -133,13 +170,23 @@ impl Action {
             }
         };
 
+        // Read auth method
+        let auth = get_input("auth method (key, agent or password, press enter for key): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+        let auth = parse_auth(&auth)?;
+
         // Read key-path
         let key= get_input("ssh-key path: ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?
             .trim().to_string();
 
-        // Read source directory
-        let source = get_input("source: ")
+        // Read where the passphrase/password is read from
+        let secret = get_input("passphrase/password (env:VAR or file:PATH, press enter for none): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+        let secret = parse_secret(&secret)?;
+
+        // Read source directories
+        let source = get_input("source (comma separated): ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?
             .trim().to_string();
 
@@ -152,10 +199,25 @@ impl Action {
         let cron_schedule = get_input("backupping schedule (Cron expression): ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?
             .trim().to_string();
+
+        // Read exclude/include patterns
+        let exclude = get_input("exclude patterns (comma separated, press enter for none): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+
+        let include = get_input("include patterns (comma separated, press enter for none): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+
         // Add Config to settings and serialize
-        let host_config = HostConfig::from(user.to_string(), identifier.to_string(), port, PathBuf::from(key), PathBuf::from(source), PathBuf::from(destination), cron_schedule.to_string());
+        let mut host_config = HostConfig::from(user.to_string(), identifier.to_string(), port, PathBuf::from(key), parse_paths(&source), PathBuf::from(destination), cron_schedule.to_string());
+        host_config.exclude = parse_patterns(&exclude);
+        host_config.include = parse_patterns(&include);
+        host_config.auth = auth;
+        host_config.secret = secret;
         println!("{}", &host_config);
 
+        // Pin the host key, after showing its fingerprint
+        self.trust_host_key(&host_config)?;
+
         settings.hosts.push(Host { hostname: hostname.clone(), config: host_config  });
 
         let _ = settings.serialize_yaml(hosts)
@@ -164,6 +226,38 @@ impl Action {
         Ok(())
     }
 
+    /// Connects to the host and pins its host key in the known hosts (trust on first use),
+    /// once the fingerprint is confirmed. Unreachable hosts are trusted on their first backup.
+    fn trust_host_key(&self, host_config: &HostConfig) -> Result<(), Trap> {
+        let sftp = Sftp::new(host_config, &self.global_config, Record::new(), false);
+        let sess = match sftp.handshake() {
+            Ok(sess) => sess,
+            Err(err) => {
+                println!("Could not reach host, its host key will be trusted on the first backup: {:?}", err);
+                return Ok(());
+            }
+        };
+
+        let identifier = &host_config.identifier;
+        let port = host_config.port.unwrap_or(22);
+        let known_hosts = known_hosts_path(&self.global_config);
+
+        if check_host_key(&sess, identifier, port, &known_hosts)? == HostKeyCheck::Match {
+            println!("Host key of {} is already known", known_host_name(identifier, port));
+            return Ok(());
+        }
+
+        println!("The {} host key fingerprint is {}", known_host_name(identifier, port), fingerprint(&sess).unwrap_or_default());
+        let answer = get_input("Trust this host key? (y/n): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            return Err(Trap::HostKey(format!("Host key of {} was not trusted", known_host_name(identifier, port))));
+        }
+
+        add_host_key(&sess, identifier, port, &known_hosts)
+    }
+
     /* del action */
 
     fn del_host(&self) -> Result<(), Trap> {
@@ -229,13 +323,23 @@ impl Action {
         let port = get_input("port (press enter for 22): ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?.trim().to_string();
 
+        // Read auth method
+        let auth = get_input("auth method (key, agent or password): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+        let auth = parse_auth(&auth)?;
+
         // Read key-path
         let key = get_input("ssh-key path: ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?
             .trim().to_string();
 
-        // Read source directory
-        let source = get_input("source: ")
+        // Read where the passphrase/password is read from
+        let secret = get_input("passphrase/password (env:VAR or file:PATH): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+        let secret = parse_secret(&secret)?;
+
+        // Read source directories
+        let source = get_input("source (comma separated): ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?
             .trim().to_string();
 
@@ -248,7 +352,14 @@ impl Action {
         let cron_schedule = get_input("backupping schedule (Cron expression): ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?.trim().to_string();
 
-        let new_host_config: HostConfig = HostConfig::from(
+        // Read exclude/include patterns
+        let exclude = get_input("exclude patterns (comma separated, `-` for none): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+
+        let include = get_input("include patterns (comma separated, `-` for none): ")
+            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+
+        let prompted_config: HostConfig = HostConfig::from(
             match user.len() {
                 0 => host_config.user.to_owned(),
                 _ => user
@@ -276,23 +387,51 @@ impl Action {
                 }
             },
             match key.len() {
-                0 => host_config.key.unwrap_or("".into()).to_owned(),
+                0 => host_config.key.clone().unwrap_or("".into()).to_owned(),
                 _ => PathBuf::from(&key), 
             },
             match source.len() {
                 0 => host_config.source.to_owned(),
-                _ => PathBuf::from(&source), 
+                _ => parse_paths(&source), 
             },
             match destination.len() {
                 0 => host_config.destination.to_owned(),
                 _ => PathBuf::from(&destination), 
             },
             match cron_schedule.len() {
-                0 => host_config.cron_schedule.unwrap_or(String::from("0 0 * * *")).to_owned(),
+                0 => host_config.cron_schedule.clone().unwrap_or(String::from("0 0 * * *")).to_owned(),
                 _ => cron_schedule
             }
         );
 
+        // Options which are not prompted for are kept as they are
+        let mut new_host_config = HostConfig {
+            user: prompted_config.user,
+            identifier: prompted_config.identifier,
+            port: prompted_config.port,
+            key: prompted_config.key,
+            source: prompted_config.source,
+            destination: prompted_config.destination,
+            cron_schedule: prompted_config.cron_schedule,
+            ..host_config.clone()
+        };
+
+        new_host_config.auth = auth.or(host_config.auth);
+        new_host_config.secret = secret.or(host_config.secret);
+
+        // Empty keeps the patterns, `-` clears them
+        new_host_config.exclude = match exclude.trim() {
+            "" => host_config.exclude,
+            "-" => None,
+            _ => parse_patterns(&exclude),
+        };
+
+        new_host_config.include = match include.trim() {
+            "" => host_config.include,
+            "-" => None,
+            _ => parse_patterns(&include),
+        };
+
         println!("{}", style.clone().bold().apply_to("New config:"));
         println!("{}", new_host_config);
 
@@ -334,18 +473,16 @@ impl Action {
             None => return Err(Trap::InvalidInput(format!("hostname `{}` is not found", hostname)))
         };
 
-        let mut snapshot = get_input("Snapshot: ")
+        let snapshot = get_input("Snapshot (name, `latest` or a time like `3 days ago`): ")
             .map_err(|err| Trap::InvalidInput(format!("Could not read input: {:?}", err)))?;
-        
-        // Making it point to the record.json file if `latest` is given
-        if snapshot.trim() == "latest" {
-            snapshot = String::from("record");
+
+        // The latest snapshot at or before a point in time, record.json for `latest`
+        let snapshot = resolve_snapshot(&self.global_config, &host_config, &snapshot)?;
+        if snapshot != "record" {
+            println!("Snapshot: {}", snapshot);
         }
 
-        let snapshot_record_path = self.global_config.backups
-            .join(host_config.identifier)
-            .join(".records")
-            .join(format!("{}.json", snapshot.trim()));
+        let snapshot_record_path = record_path(&self.global_config, &host_config, &snapshot);
 
         /* Compiling snapshot */
         let mut compiler = Compiler::from(&snapshot_record_path)?;
@@ -389,14 +526,76 @@ impl Action {
         let list_method = match self.operands[1].to_lowercase().as_str() {
             "snapshots" | "s" | "snap" => ViewSubject::Snapshots,
             "config"    | "c" | "conf" => ViewSubject::Config,
+            "deletions" | "d" | "del"  => ViewSubject::Deletions,
             _ => return Err(Trap::InvalidInput(format!("List Method: `{}` is not recognized in this action", self.operands[0])))
         };
 
         match list_method {
             ViewSubject::Snapshots => self.view_snapshots()?,
             ViewSubject::Config    => self.view_config()?,
+            ViewSubject::Deletions => self.view_deletions()?,
+        }
+
+        Ok(())
+    }
+
+    /* Key action */
+
+    /// Initialises or rotates the repository key, which the archives and records are encrypted with,
+    /// or encrypts the data written before encryption was set up.
+    /// The key is unlocked by the `encryption` secret of the global config.
+    fn key(&self) -> Result<(), Trap> {
+        if self.operands.len() != 1 {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
         }
 
+        let key_command = match self.operands[0].to_lowercase().as_str() {
+            "init"   | "i" => KeyCommand::Init,
+            "rotate" | "r" => KeyCommand::Rotate,
+            "migrate" | "m" => KeyCommand::Migrate,
+            _ => return Err(Trap::InvalidInput(format!("Key command: `{}` is not recognized in this action", self.operands[0])))
+        };
+
+        let secret = self.global_config.encryption.as_ref()
+            .ok_or(Trap::Config(String::from("Set `encryption` (env:VAR or file:PATH) in /etc/rensen/rensen_config.yml first")))?;
+
+        let key_path = crypto::key_path(&self.global_config);
+        let style = Style::new();
+
+        let unlocking_secret = match key_command {
+            KeyCommand::Init => {
+                crypto::init_key(&key_path, &secret.read()?)?;
+                println!("{} repository key at {:?}", style.clone().bold().green().apply_to("Initialised"), key_path);
+                secret.clone()
+            },
+            KeyCommand::Rotate => {
+                let new_secret = get_input("new passphrase/keyfile (env:VAR or file:PATH, press enter to keep the current one): ")
+                    .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?;
+                let new_secret = parse_secret(&new_secret)?.unwrap_or(secret.clone());
+
+                let id = crypto::rotate_key(&key_path, &secret.read()?, &new_secret.read()?)?;
+                println!("{} repository key, new data is encrypted with key {}", style.clone().bold().green().apply_to("Rotated"), id);
+
+                if &new_secret != secret {
+                    println!("{} Set `encryption: {}` in /etc/rensen/rensen_config.yml", style.clone().bold().yellow().apply_to("Note:"), new_secret);
+                }
+                new_secret
+            },
+            // Encrypting what was written before encryption was set up, with the unlocked key
+            KeyCommand::Migrate => {
+                let (files, bytes) = crypto::migrate(&self.global_config)?;
+                let bytes: MemoryUsage = format_bytes(bytes);
+                println!("{} {} files ({} {})", style.clone().bold().green().apply_to("Encrypted"), files, bytes.amount, bytes.unit);
+                return Ok(());
+            },
+        };
+
+        // Encrypting with the current key from now on, without restarting ctl
+        crypto::install(Some(crypto::unlock(&key_path, &unlocking_secret.read()?)?));
         Ok(())
     }
 
@@ -431,6 +630,52 @@ impl Action {
     }
 
     // Lists all snapshots/backups taken of host
+    /// Lists the files deleted from the host, by the snapshot the deletion was observed in
+    fn view_deletions(&self) -> Result<(), Trap> {
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands[0];
+
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
+        };
+
+        let record_path = record_path(&self.global_config, &host_config, "latest");
+        let record = Record::deserialize_json(&record_path)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize record: {}", err)))?;
+
+        // snapshot: deleted files, the ones recorded before it was kept first
+        let mut deletions: BTreeMap<Option<&String>, Vec<(&PathBuf, &DeletedEntry)>> = BTreeMap::new();
+        for (source, deleted) in &record.snapshot.deleted_entries {
+            deletions.entry(deleted.deleted_in.as_ref()).or_default().push((source, deleted));
+        }
+
+        let style = Style::new();
+        for (snapshot, deleted) in deletions {
+            match snapshot {
+                Some(snapshot) => println!("{}", style.clone().bold().blue().apply_to(snapshot)),
+                None => println!("{}", style.clone().bold().blue().apply_to("Before deletions were tracked")),
+            }
+
+            for (source, deleted) in deleted {
+                if snapshot.is_none() {
+                    println!("->  {:?}", source);
+                    continue;
+                }
+
+                let mem_size: MemoryUsage = format_bytes(deleted.entry.size);
+                let last = deleted.entry.snapshot_path.file_name().unwrap_or_default().to_string_lossy();
+                println!("->  {:?} {} {} (last backed up in {})", source, mem_size.amount, mem_size.unit, last);
+            }
+        }
+        println!();
+
+        Ok(())
+    }
+
     fn view_snapshots(&self) -> Result<(), Trap> {
         if self.operands.len() != 2 {
             return Err(
@@ -488,7 +733,8 @@ impl Action {
 
                 // Filtering out the record.json file
                 if file_stem != "record" {
-                    println!("->  {} {} {}", style.clone().bold().blue().apply_to(file_stem.to_str().unwrap()), mem_size.amount, mem_size.unit);
+                    let kind = record.kind.map(|kind| format!(" ({})", kind)).unwrap_or_default();
+                    println!("->  {} {} {}{}", style.clone().bold().blue().apply_to(file_stem.to_str().unwrap()), mem_size.amount, mem_size.unit, kind);
                 }
             }
 
@@ -498,6 +744,236 @@ impl Action {
         Ok(())
     }
 
+    /* prune action */
+
+    /// Applies the retention of the host to its snapshots, like after every backup
+    fn prune(&self) -> Result<(), Trap> {
+        if self.operands.len() != 1 {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands[0];
+
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
+        };
+
+        if host_config.retention.is_none() {
+            return Err(Trap::Config(format!("No retention is set for `{}`, every snapshot is kept", hostname)));
+        }
+
+        let report = prune(&self.global_config, &host_config)?;
+
+        let style = Style::new();
+        for snapshot in &report.pruned {
+            println!("{} {}", style.clone().bold().red().apply_to("Pruned"), snapshot);
+        }
+        for snapshot in &report.kept {
+            println!("{} {}", style.clone().bold().blue().apply_to("Kept  "), snapshot);
+        }
+
+        let freed: MemoryUsage = format_bytes(report.freed);
+        println!("Deleted {} archives and {} chunks, freeing {} {}", report.archives, report.chunks, freed.amount, freed.unit);
+
+        Ok(())
+    }
+
+    /* synthesize action */
+
+    /// Builds a full backup of the host from its latest record, without contacting it
+    fn synthesize(&self) -> Result<(), Trap> {
+        if self.operands.len() != 1 {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands[0];
+
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
+        };
+
+        print!("Synthesizing ...");
+        let snapshot = synthesize_full(&self.global_config, &host_config)?;
+        println!("Done");
+
+        let style = Style::new();
+        println!("{} full backup {} of {}", style.clone().bold().green().apply_to("Synthesized"), snapshot, hostname);
+
+        Ok(())
+    }
+
+    /* restore action */
+
+    /// Pushes the files of a snapshot back to the host, or to another one, over SFTP
+    fn restore(&self) -> Result<(), Trap> {
+        if self.operands.len() != 1 {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands[0];
+
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
+        };
+
+        let read_err = |err| Trap::ReadInput(format!("Could not read input: {:?}", err));
+
+        let snapshot = get_input("Snapshot (name or a time like `3 days ago`, press enter for latest): ").map_err(read_err)?;
+        let paths = get_input("Paths (comma separated, press enter for all): ").map_err(read_err)?;
+        let target_host = get_input("Target host (press enter for the same host): ").map_err(read_err)?;
+        let target_path = get_input("Target path (press enter for the original paths): ").map_err(read_err)?;
+        let conflict = get_input("Existing files (overwrite, skip or rename, press enter for skip): ").map_err(read_err)?;
+        let dry_run = get_input("Dry run? (y/n): ").map_err(read_err)?;
+
+        let target_config = match target_host.trim() {
+            "" => host_config.clone(),
+            target_host => match settings.associated_config(&target_host.to_string()) {
+                Some(config) => config,
+                None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", target_host)))
+            },
+        };
+
+        let conflict = match conflict.trim().to_lowercase().as_str() {
+            "" | "skip" | "s" => ConflictPolicy::Skip,
+            "overwrite" | "o" => ConflictPolicy::Overwrite,
+            "rename" | "r"    => ConflictPolicy::Rename,
+            other => return Err(Trap::InvalidInput(format!("Not a recognized conflict policy: `{}`", other)))
+        };
+
+        let options = RestoreOptions {
+            snapshot: match snapshot.trim() {
+                "" => String::from("latest"),
+                snapshot => snapshot.to_string(),
+            },
+            paths: parse_paths(&paths),
+            target_path: match target_path.trim() {
+                "" => None,
+                target_path => Some(PathBuf::from(target_path)),
+            },
+            conflict,
+            dry_run: dry_run.trim().to_lowercase().starts_with('y'),
+        };
+
+        let report = restore(&self.global_config, &host_config, &target_config, &options)?;
+
+        let style = Style::new();
+        println!("Snapshot: {}", report.snapshot);
+        for item in &report.items {
+            let action = match item.action {
+                RestoreAction::Create    => style.clone().bold().green().apply_to("Create   "),
+                RestoreAction::Overwrite => style.clone().bold().yellow().apply_to("Overwrite"),
+                RestoreAction::Rename    => style.clone().bold().blue().apply_to("Rename   "),
+                RestoreAction::Skip      => style.clone().bold().dim().apply_to("Skip     "),
+            };
+
+            if item.source == item.destination {
+                println!("{} {:?}", action, item.source);
+            } else {
+                println!("{} {:?} -> {:?}", action, item.source, item.destination);
+            }
+        }
+
+        for (destination, err) in &report.failed {
+            println!("{} {:?}: {:?}", style.clone().bold().red().apply_to("Failed   "), destination, err);
+        }
+
+        let restored = report.items.iter().filter(|item| item.action != RestoreAction::Skip).count();
+        if options.dry_run {
+            println!("Dry run, {} of {} entries would be restored to {}", restored, report.items.len(), target_config.identifier);
+        } else {
+            println!("Restored {} of {} entries to {}", restored.saturating_sub(report.failed.len()), report.items.len(), target_config.identifier);
+        }
+
+        Ok(())
+    }
+
+    /* extract action */
+
+    /// Streams a single file of a snapshot to stdout, or to a local path
+    fn extract(&self) -> Result<(), Trap> {
+        // A point in time may span several operands (`3 days ago`), up to the absolute source path
+        let source_index = self.operands.iter().skip(2).position(|operand| operand.starts_with('/')).map(|i| i + 2);
+        let source_index = match source_index {
+            Some(i) if self.operands.len() - i <= 2 => i,
+            _ => return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            ),
+        };
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands[0];
+        let snapshot = self.operands[1..source_index].join(" ");
+        let source = PathBuf::from(&self.operands[source_index]);
+
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
+        };
+
+        let mut destination = match self.operands.get(source_index + 1) {
+            Some(destination) => PathBuf::from(destination),
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                restore_file(&self.global_config, &host_config, &snapshot, &source, &mut stdout)?;
+
+                // Back to the prompt on a line of its own, leaving piped content as it is
+                if stdout.is_terminal() {
+                    println!();
+                }
+                return Ok(());
+            },
+        };
+
+        // Into a directory by the name of the file
+        if destination.is_dir() {
+            if let Some(file_name) = source.file_name() {
+                destination = destination.join(file_name);
+            }
+        }
+
+        let mut file = fs::File::create(&destination)
+            .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", destination, err)))?;
+        let size = restore_file(&self.global_config, &host_config, &snapshot, &source, &mut file)?;
+
+        let style = Style::new();
+        let size: MemoryUsage = format_bytes(size);
+        println!("{} {:?} to {:?} ({} {})", style.clone().bold().green().apply_to("Extracted"), source, destination, size.amount, size.unit);
+
+        Ok(())
+    }
+
     /* run action */
 
     fn run_backup(&self) -> Result<(), Trap> {
@@ -536,18 +1012,16 @@ impl Action {
         // Check if second arguement is `full` or is `inc`.
         // Running manual backup based on that.
         let backup_method = match self.operands[1].to_lowercase().as_str() {
-            "full" => BackupMethod::Full,
-            "inc"  => BackupMethod::Incremental,
+            "full" | "f"                  => BackupMethod::Full,
+            "incremental" | "inc" | "i"   => BackupMethod::Incremental,
+            "differential" | "diff" | "d" => BackupMethod::Differential,
             _ => return Err(Trap::InvalidInput(format!("Not a regognozed backup method")))
         };
 
-        if backup_method == BackupMethod::Incremental {
-            sftp.incremental = true;
-            sftp.backup()?;
-            return Ok(())
-        }
-
-        sftp.backup()?; 
+        // Differential backups are compared against the last full backup instead of the latest snapshot
+        sftp.incremental = backup_method == BackupMethod::Incremental;
+        sftp.differential = backup_method == BackupMethod::Differential;
+        sftp.backup()?;
 
         Ok(())
     }
@@ -571,26 +1045,51 @@ impl Action {
                 },
                 "mod"     => {
                     println!("m, mod <hostname>     Enters modification interface.");
-                    println!("Allows you to modify a config for a host that already exists instead of readding it.");
+                    println!("Allows you to modify a config for a host that already exists instead of readding it.\nEmpty answers keep the current value, `-` clears the exclude and include patterns.");
                 },
                 "run"     => {
-                    println!("r, run <hostname> <inc, full>   Runs backup for host based on what is specified in config."); 
-                    println!("Runs the rensen backup system, either incremental or full backups. Backupped files will be stored\nat path specified in /etc/rensen/rensen_config.yml\n");
-                    println!("\nAliases:\nincremental, inc, i\nfull, f");
+                    println!("r, run <hostname> <inc, diff, full>   Runs backup for host based on what is specified in config."); 
+                    println!("Runs the rensen backup system, either incremental, differential or full backups. Backupped files will be stored\nat path specified in /etc/rensen/rensen_config.yml\n");
+                    println!("Differential backups copy everything changed since the last full backup, so restoring needs at most two snapshots.");
+                    println!("\nAliases:\nincremental, inc, i\ndifferential, diff, d\nfull, f");
                 },
                 "list"    => {
                     println!("l, list    lists out all hosts.");
 
                 },
                 "view"    => {
-                    println!("v, view <hostname> <snapshots, config>     views snapshots taken of host.");
+                    println!("v, view <hostname> <snapshots, config, deletions>     views snapshots taken of host.");
                     println!("\nsnapshots: \nThis checks the snapshots/backups taken of the host at the location specified in /etc/rensen/rensen_config.yml");
                     println!("\nconfig: \nEchos out the deserialized format of the config file, stored at location specified in /etc/rensen/rensne_config.yml");
-                    println!("\nAliases: \nsnapshots, snap, s\nconfig, conf, c"); 
+                    println!("\ndeletions: \nLists the files deleted from the host, by the snapshot in which the deletion was found. A deleted file can still be\nrestored with `extract`, from the snapshot it was last backed up in, which pruning keeps while a record lists the deletion.");
+                    println!("\nAliases: \nsnapshots, snap, s\nconfig, conf, c\ndeletions, del, d"); 
+                },
+                "key"     => {
+                    println!("k, key <init, rotate, migrate>     Initialises or rotates the repository key.");
+                    println!("Archives and records are encrypted with the repository key, which is unlocked by the passphrase or keyfile\nset as `encryption` in /etc/rensen/rensen_config.yml. Rotating adds a new key for new data, earlier data stays readable.");
+                    println!("Unencrypted data is refused once encryption is set, migrating encrypts the backups taken before it was\n(with rensend stopped). Until then, set `allow_plaintext: true` to read them.");
+                    println!("\nAliases:\ninit, i\nrotate, r\nmigrate, m");
+                },
+                "prune"   => {
+                    println!("p, prune <hostname>     Prunes snapshots of host by its retention.");
+                    println!("Deletes the snapshots which the `retention` of the host does not keep, as is done after every backup.\nArchives which later snapshots still have files in are kept until no snapshot refers to them.");
+                },
+                "synth"   => {
+                    println!("s, synth <hostname>     Synthesizes a full backup of host.");
+                    println!("Builds a new self-contained snapshot from the latest record of the host, without connecting to it.\nRestoring it needs no earlier snapshots, so those can be pruned.");
+                },
+                "restore" => {
+                    println!("restore <hostname>     Enters restore interface.");
+                    println!("Restores the files of a snapshot to the host over SFTP, with their permissions and times. Asks for the paths\nto restore (all if none), another host or directory to restore to, and what to do with files which exist already:\noverwrite, skip, or rename (restored next to them as `<name>.restored-<snapshot>`). A dry run only lists what would be done.");
+                },
+                "extract" => {
+                    println!("x, extract <hostname> <snapshot> <path> [local path]     Extracts a single file of a snapshot.");
+                    println!("Writes the file at <path> on the host, as it was in <snapshot> (or `latest`), to stdout or to [local path].\nThe snapshot can also be a point in time: `3 days ago` or `2026-10-01 14:00`.\nOnly that file is read from the archive it is in, the rest of the snapshot is not extracted.");
                 },
                 "compile" => {
                     println!("c, comp <hostname>     Starts compilation interface.");
                     println!("Starts the interface for compilation, where you need to specify a snapshot from what is available in `list` action.");
+                    println!("Instead of its name, a snapshot can be given by a point in time: `3 days ago`, `2026-10-01 14:00` or a unix timestamp,\nfor the latest snapshot taken at or before it. Files deleted since are in it as well.");
                 },
                 _ => println!("Not a regognized action"),
             }
@@ -605,10 +1104,15 @@ impl Action {
         println!("a, add <hostname>                      Enter host-adding interface.");
         println!("d, del <hostname>                      Deletes host config.");
         println!("m, mod <hostname>                      Enter modification interface.");
-        println!("r, run <hostname> <inc, full>          Run backup for host machine.");
+        println!("r, run <hostname> <inc, diff, full>    Run backup for host machine.");
         println!("l, list                                Lists all hosts on system.");
-        println!("v, view <hostname> <snapshots, config> views snapshots taken of host or echos config file.");
+        println!("v, view <hostname> <snapshots, config, deletions> views snapshots taken of host, echos config file or lists deleted files.");
         println!("c, comp <hostname>                     Start compilation interface.");
+        println!("k, key <init, rotate, migrate>         Initialise or rotate the repository key, or encrypt earlier backups.");
+        println!("p, prune <hostname>                    Prune snapshots of host by its retention.");
+        println!("s, synth <hostname>                    Synthesize a full backup of host from its snapshots.");
+        println!("restore <hostname>                     Enter restore interface.");
+        println!("x, extract <hostname> <snapshot> <path> [local path]   Extract a single file of a snapshot.");
     }
 }
 
@@ -7,6 +7,7 @@ use std::path::PathBuf;
 use rensen_lib::logging::*;
 use rensen_lib::config::GlobalConfig;
 use rensen_lib::traits::YamlFile;
+use rensen_lib::crypto;
 
 // Action
 pub mod action;
@@ -99,6 +100,11 @@ impl Ctl {
             "m" | "mod"           => ActionType::ModifyHost,
             "r" | "run"           => ActionType::RunBackup,
             "c" | "comp"          => ActionType::Compile,
+            "k" | "key"           => ActionType::Key,
+            "p" | "prune"         => ActionType::Prune,
+            "s" | "synth"         => ActionType::Synthesize,
+            "restore"             => ActionType::Restore,
+            "x" | "extract"       => ActionType::Extract,
             "clear"               => ActionType::Clear,
             "h" | "?" | "help"    => ActionType::Help,
             "q" | "quit" | "exit" => ActionType::Exit,
@@ -130,6 +136,17 @@ fn main() -> std::io::Result<()> {
     };
 
     ctl.clear_screen();
+
+    // Archives and records are encrypted once the repository key is unlocked
+    crypto::configure(&ctl.global_config);
+    match crypto::unlock_repository(&ctl.global_config) {
+        Ok(keys) => crypto::install(keys),
+        Err(err) => {
+            log_trap(&ctl.global_config, &err);
+            println!("Could not unlock the repository key, run `key init` if it is not initialised yet: {:?}\n", err);
+        }
+    }
+
     let _ = ctl.start();
 
     Ok(())
@@ -1,6 +1,8 @@
 use std::io::{self, Write, BufRead};
 use std::fmt;
 use std::path::PathBuf;
+use rensen_lib::config::{AuthMethod, Secret};
+use rensen_lib::logging::Trap;
 
 pub fn get_input(prompt: &str) -> Result<String, io::Error> {
     print!("{}", prompt);
@@ -12,6 +14,67 @@ pub fn get_input(prompt: &str) -> Result<String, io::Error> {
     Ok(buffer)
 }
 
+/// Splits comma separated patterns from input,
+/// returns None if there are no patterns.
+pub fn parse_patterns(input: &str) -> Option<Vec<String>> {
+    let patterns: Vec<String> = input
+        .split(',')
+        .map(|pattern| pattern.trim().to_string())
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
+
+    match patterns.len() {
+        0 => None,
+        _ => Some(patterns),
+    }
+}
+
+/// Splits comma separated paths from input
+pub fn parse_paths(input: &str) -> Vec<PathBuf> {
+    parse_patterns(input)
+        .unwrap_or_default()
+        .into_iter()
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Parses the auth method (key, agent or password),
+/// returns None if input is empty.
+pub fn parse_auth(input: &str) -> Result<Option<AuthMethod>, Trap> {
+    match input.trim().to_lowercase().as_str() {
+        "" => Ok(None),
+        "key" => Ok(Some(AuthMethod::Key)),
+        "agent" => Ok(Some(AuthMethod::Agent)),
+        "password" => Ok(Some(AuthMethod::Password)),
+        other => Err(Trap::InvalidInput(format!("Unknown auth method `{}`, use key, agent or password", other))),
+    }
+}
+
+/// Parses where a secret is read from, `env:VAR` or `file:PATH`,
+/// returns None if input is empty.
+pub fn parse_secret(input: &str) -> Result<Option<Secret>, Trap> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(None);
+    }
+
+    match input.split_once(':') {
+        Some(("env", var)) => Ok(Some(Secret::Env(var.trim().to_string()))),
+        Some(("file", path)) => Ok(Some(Secret::File(PathBuf::from(path.trim())))),
+        _ => Err(Trap::InvalidInput(format!("Invalid secret `{}`, use env:VAR or file:PATH", input))),
+    }
+}
+
+#[test]
+fn test_parse_secret() {
+    assert_eq!(parse_auth(" Agent\n").unwrap(), Some(AuthMethod::Agent));
+    assert!(parse_auth("kerberos").is_err());
+    assert_eq!(parse_secret("env:RENSEN_PASS").unwrap(), Some(Secret::Env(String::from("RENSEN_PASS"))));
+    assert_eq!(parse_secret("file: /etc/rensen/secret\n").unwrap(), Some(Secret::File(PathBuf::from("/etc/rensen/secret"))));
+    assert_eq!(parse_secret("\n").unwrap(), None);
+    assert!(parse_secret("hunter2").is_err());
+}
+
 #[derive(PartialEq, Debug)]
 pub enum ByteUnit {
     B,
@@ -1,12 +1,14 @@
 use rensen_lib::config::*;
 use rensen_lib::traits::*;
 use rensen_lib::logging::*;
+use rensen_lib::crypto;
 
 pub mod scheduler;
 pub mod utils;
 pub mod tasks;
 
 use crate::scheduler::*;
+use crate::tasks::TaskKind;
 
 use cron::Schedule;
 use std::sync::Arc;
@@ -26,7 +28,7 @@ fn parse_schedules(global_config: &GlobalConfig, settings: &Settings) -> Result<
             // Parse cron expression and push to vector which will await its time for exec
             match Schedule::from_str(cron_schedule) {
                 Ok(schedule) => {
-                    let host_schedule = Arc::new(WSchedule { host: host.clone().into(), schedule });
+                    let host_schedule = Arc::new(WSchedule { host: host.clone().into(), schedule, kind: TaskKind::Incremental });
                     println!("host_schedule: {:?}", host_schedule);
                     schedules.push(host_schedule);
                 },
@@ -40,10 +42,30 @@ fn parse_schedules(global_config: &GlobalConfig, settings: &Settings) -> Result<
             let host_schedule = Arc::new(WSchedule {
                 host: host.clone().into(),
                 schedule: Schedule::from_str("0 0 0 * *").unwrap(),
+                kind: TaskKind::Incremental,
             });
 
             schedules.push(host_schedule);
-            continue;
+        }
+
+        // Full and differential backups, and synthetic ones consolidating the snapshots of the host
+        let extra_schedules = [
+            (&host.config.full_schedule, TaskKind::Full),
+            (&host.config.diff_schedule, TaskKind::Differential),
+            (&host.config.synthesize_schedule, TaskKind::Synthesize),
+        ];
+
+        for (cron_schedule, kind) in extra_schedules {
+            if let Some(cron_schedule) = cron_schedule {
+                match Schedule::from_str(cron_schedule) {
+                    Ok(schedule) => {
+                        schedules.push(Arc::new(WSchedule { host: host.clone().into(), schedule, kind }));
+                    },
+                    Err(err) => {
+                        log_trap(global_config, &Trap::InvalidInput(format!("Invalid Cron Expression ({:?}) for `{}`: {}", kind, host.hostname, err)));
+                    }
+                }
+            }
         }
     }
 
@@ -56,6 +78,10 @@ async fn main() -> Result<(), Trap> {
     let global_config: GlobalConfig = GlobalConfig::deserialize_yaml(&global_config_path)
         .map_err(|err| Trap::FS(format!("Could not deserialize Global Config: {}", err)))?;
 
+    // Archives and records are encrypted with the repository key, if encryption is configured
+    crypto::configure(&global_config);
+    crypto::install(crypto::unlock_repository(&global_config)?);
+
     let settings = Settings::deserialize_yaml(&global_config.hosts)
         .map_err(|err| Trap::FS(format!("Could not deserialize Settings @ {:?}: {}", global_config.hosts, err)))?;
 
@@ -15,6 +15,7 @@ use crate::tasks::*;
 pub struct WSchedule {
     pub host: Arc<Host>, 
     pub schedule: Schedule,
+    pub kind: TaskKind,
 }
 
 pub struct Scheduler {
@@ -88,12 +89,19 @@ impl Scheduler {
             interval.tick().await;
             let now = Local::now();
 
-            for schedule in self.schedules.iter() {
-                if self.should_run(&now, &schedule) {
+            let due: Vec<&Arc<WSchedule>> = self.schedules.iter()
+                .filter(|schedule| self.should_run(&now, schedule))
+                .collect();
 
+            for schedule in due.iter() {
+                // Of the backups due for a host at the same time, only the most complete one runs
+                let replaced = due.iter()
+                    .any(|other| other.host.hostname == schedule.host.hostname && other.kind.replaces(schedule.kind));
+
+                if !replaced {
                     let global_config_clone = Arc::clone(&self.global_config);
                     let host = Arc::clone(&schedule.host); 
-                    let backup_task = BackupTask { global_config: global_config_clone, host };
+                    let backup_task = BackupTask { global_config: global_config_clone, host, kind: schedule.kind };
 
                     // self.queue.lock().unwrap().pushb(backup_task);
 
@@ -3,14 +3,33 @@ use rensen_lib::config::*;
 use rensen_lib::traits::*;
 use rensen_lib::logging::*;
 use rensen_lib::record::*;
+use rensen_lib::synthesize::synthesize_full;
 
 use std::sync::Arc;
 
+/// What a scheduled task does for its host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskKind {
+    Incremental,  // full instead when the host's full_every is due
+    Differential, // changes since the last full backup
+    Full,
+    Synthesize,   // synthetic full backup from the record, without contacting the host
+}
+
+impl TaskKind {
+    /// If self runs instead of other when both are due for a host at the same time,
+    /// the more complete backup covering the other.
+    pub fn replaces(&self, other: TaskKind) -> bool {
+        matches!((self, other), (TaskKind::Full, TaskKind::Incremental | TaskKind::Differential) | (TaskKind::Differential, TaskKind::Incremental))
+    }
+}
+
 // Struct for running the actual backup task
 #[derive(Debug)]
 pub struct BackupTask {
     pub global_config: Arc<GlobalConfig>, 
     pub host: Arc<Host>, 
+    pub kind: TaskKind,
 }
 
 impl BackupTask {
@@ -19,9 +38,14 @@ impl BackupTask {
     pub async fn run(&self) -> Result<(), Trap> {
 
         let hostname = &self.host.hostname;
-        let inc = true;
         let host_config = &self.host.config;
 
+        if self.kind == TaskKind::Synthesize {
+            let snapshot = synthesize_full(&self.global_config, host_config)?;
+            log_message(&self.global_config, &format!("Synthesized full backup {} of `{}`", snapshot, hostname));
+            return Ok(());
+        }
+
         let record_path = host_config.destination
             .join(&host_config.identifier)
             .join(".records")
@@ -30,7 +54,10 @@ impl BackupTask {
         let record = Record::deserialize_json(&record_path)
             .map_err(|err| Trap::FS(format!("Could not read record for host `{}`: {}", hostname, err)))?;
 
+        let full = self.kind == TaskKind::Full || record.is_full_due(host_config.full_every);
+        let inc = !full;
         let mut sftp = Sftp::new(&host_config, &self.global_config, record, inc);
+        sftp.differential = !full && self.kind == TaskKind::Differential;
 
         sftp.incremental = inc;
         sftp.backup()?;
@@ -0,0 +1,675 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use serde::{Serialize, Deserialize};
+use flate2::write::GzEncoder;
+use flate2::read::MultiGzDecoder;
+use flate2::Compression;
+use xz2::write::XzEncoder;
+use xz2::read::XzDecoder;
+use tar::{Builder, Header, EntryType};
+
+use crate::snapshot::FileEntry;
+use crate::crypto::{self, Encryptor, decrypting_reader, is_encrypted};
+use crate::utils::{set_header_ownership, append_pax_extensions, append_special_entries};
+
+/// Uncompressed bytes after which the compression stream of an archive is restarted,
+/// before the next member. Members are read from the start of their stream (see `MemberIndex`),
+/// so no more than this is decompressed in vain.
+const FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Extensions of file types which are compressed already (media, archives, office documents).
+/// Compressing them again costs time while saving next to nothing.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "m4a", "aac", "ogg", "opus", "flac",
+    "mp4", "m4v", "mkv", "mov", "avi", "webm",
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "jar", "apk",
+    "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub",
+];
+
+/// Returns true if path is of a type which is compressed already, by its extension
+pub fn is_compressed_media(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| COMPRESSED_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Compression of an archive. Recorded with every entry, so the archive it is in
+/// is found and decompressed with the codec it was written with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Gzip,
+    Zstd,
+    Xz,
+    None,
+}
+
+impl Codec {
+    /// Extension of archives with this codec, `tar.gz` for gzip
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "tar.gz",
+            Codec::Zstd => "tar.zst",
+            Codec::Xz => "tar.xz",
+            Codec::None => "tar",
+        }
+    }
+
+    /// Level used when none is configured
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Codec::Gzip => 6,
+            Codec::Zstd => 3,
+            Codec::Xz => 6,
+            Codec::None => 0,
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Gzip => write!(f, "gzip"),
+            Codec::Zstd => write!(f, "zstd"),
+            Codec::Xz => write!(f, "xz"),
+            Codec::None => write!(f, "none"),
+        }
+    }
+}
+
+/// The archive of snapshot_path (a snapshot root without extension) with codec
+pub fn archive_path(snapshot_path: &Path, codec: Codec) -> PathBuf {
+    PathBuf::from(format!("{}.{}", snapshot_path.display(), codec.extension()))
+}
+
+/// Opens the archive at path, decrypting it if it is encrypted and decompressing it with codec
+pub fn open_archive(path: &Path, codec: Codec) -> io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = decrypting_reader(File::open(path)?)?;
+    Ok(tar::Archive::new(decoder(file, codec)?))
+}
+
+/// Decompresses data with codec. Archives are made up of several compression streams
+/// (see `FRAME_SIZE`), which are read one after the other.
+fn decoder<R: Read + 'static>(data: R, codec: Codec) -> io::Result<Box<dyn Read>> {
+    let data = BufReader::new(data);
+
+    Ok(match codec {
+        Codec::Gzip => Box::new(MultiGzDecoder::new(data)),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(data)?),
+        Codec::Xz => Box::new(XzDecoder::new_multi_decoder(data)),
+        Codec::None => Box::new(data),
+    })
+}
+
+/// The member index of the archive at archive_path
+pub fn index_path(archive_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.index", archive_path.display()))
+}
+
+/// Where a member is in its archive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MemberLocation {
+    pub frame: u64,  // offset of the compression stream the member is in, in the (decrypted) archive
+    pub offset: u64, // offset of the headers of the member in the decompressed stream
+    pub size: u64,
+}
+
+/// The files of an archive by member name, written next to it by `ArchiveWriter`.
+/// A member which is in the archive more than once (see `ArchiveWriter::append_file`)
+/// is at its last location.
+///
+/// A member is read by seeking to its compression stream and decompressing it up to the
+/// member, instead of decompressing the archive from its start.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MemberIndex {
+    pub members: BTreeMap<String, MemberLocation>,
+}
+
+impl MemberIndex {
+    /// The index of the archive at archive_path, None for archives without one
+    /// (written before archives were indexed)
+    pub fn load(archive_path: &Path) -> io::Result<Option<Self>> {
+        let file = match File::open(index_path(archive_path)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let index = serde_json::from_reader(decrypting_reader(file)?)?;
+        Ok(Some(index))
+    }
+
+    /// Written (encrypted with the installed keys) to a temp file and moved into place
+    fn write(&self, archive_path: &Path) -> io::Result<()> {
+        let path = index_path(archive_path);
+        let temp_path = PathBuf::from(format!("{}.{}.tmp", path.display(), process::id()));
+
+        let written = File::create(&temp_path)
+            .and_then(|file| match crypto::installed() {
+                Some(keys) => {
+                    let mut encryptor = Encryptor::new(file, &keys)?;
+                    serde_json::to_writer(&mut encryptor, self)?;
+                    encryptor.finish()?.sync_all()
+                },
+                None => {
+                    let mut file = file;
+                    serde_json::to_writer(&mut file, self)?;
+                    file.sync_all()
+                },
+            })
+            .and_then(|_| fs::rename(&temp_path, &path));
+
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+
+        written
+    }
+}
+
+/// Streams the file member of the archive at path to out, returning its size,
+/// or None if the archive has no such member.
+///
+/// With a member index only the compression stream the member is in is decompressed
+/// (and for unencrypted archives, only read). Archives without one are scanned to the end
+/// for the last occurrence of the member, as a member appended again supersedes the earlier one,
+/// and read again up to it.
+pub fn copy_member<W: Write>(path: &Path, codec: Codec, member: &Path, out: &mut W) -> io::Result<Option<u64>> {
+    let name = member.to_string_lossy();
+    let location = match MemberIndex::load(path)? {
+        Some(mut index) => match index.members.remove(name.as_ref()) {
+            Some(location) => location,
+            None => return Ok(None),
+        },
+        None => {
+            let mut last = None;
+            for (position, entry) in open_archive(path, codec)?.entries()?.enumerate() {
+                if entry?.path()?.as_ref() == member {
+                    last = Some(position);
+                }
+            }
+
+            let position = match last {
+                Some(position) => position,
+                None => return Ok(None),
+            };
+
+            let mut archive = open_archive(path, codec)?;
+            let mut entry = archive.entries()?.nth(position)
+                .ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Archive {:?} ends before member {:?}", path, member)))??;
+
+            return io::copy(&mut entry, out).map(Some);
+        },
+    };
+
+    // The compression stream of the member, seeked to unless it has to be decrypted
+    let mut file = File::open(path)?;
+    let mut magic = Vec::new();
+    (&mut file).take(8).read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let stream: Box<dyn Read> = if is_encrypted(&magic) {
+        let mut data = decrypting_reader(file)?;
+        io::copy(&mut (&mut data).take(location.frame), &mut io::sink())?;
+        data
+    } else {
+        crypto::check_plaintext()?;
+        file.seek(SeekFrom::Start(location.frame))?;
+        Box::new(file)
+    };
+
+    let mut stream = decoder(stream, codec)?;
+    let skipped = io::copy(&mut (&mut stream).take(location.offset), &mut io::sink())?;
+    if skipped != location.offset {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Archive {:?} ends before member {:?}", path, member)));
+    }
+
+    let mut archive = tar::Archive::new(stream);
+    let mut entry = archive.entries()?.next()
+        .ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Archive {:?} ends before member {:?}", path, member)))??;
+
+    if entry.path()?.as_ref() != member {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Index of archive {:?} does not match it at {:?}", path, member)));
+    }
+
+    io::copy(&mut entry.by_ref().take(location.size), out).map(Some)
+}
+
+/// File an archive is written to, encrypted if keys are installed
+pub enum Output {
+    Plain(BufWriter<File>),
+    Encrypted(Encryptor<BufWriter<File>>),
+}
+
+impl Output {
+    fn new(file: File) -> io::Result<Self> {
+        let writer = BufWriter::new(file);
+
+        Ok(match crypto::installed() {
+            Some(keys) => Output::Encrypted(Encryptor::new(writer, &keys)?),
+            None => Output::Plain(writer),
+        })
+    }
+
+    /// Seals the last chunk if encrypted
+    fn finish(self) -> io::Result<BufWriter<File>> {
+        match self {
+            Output::Plain(writer) => Ok(writer),
+            Output::Encrypted(encryptor) => encryptor.finish(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(writer) => writer.write(buf),
+            Output::Encrypted(encryptor) => encryptor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Encrypted(encryptor) => encryptor.flush(),
+        }
+    }
+}
+
+/// Output counting the bytes written to it, before encryption
+pub struct Counted {
+    output: Output,
+    written: u64,
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.output.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// A compression stream
+enum Stream {
+    Gzip(GzEncoder<Counted>),
+    Zstd(zstd::Encoder<'static, Counted>),
+    Xz(XzEncoder<Counted>),
+    None(Counted),
+}
+
+impl Stream {
+    /// Levels out of range for the codec are clamped
+    fn new(writer: Counted, codec: Codec, level: i32) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::Gzip => Stream::Gzip(GzEncoder::new(writer, Compression::new(level.clamp(0, 9) as u32))),
+            Codec::Zstd => {
+                let range = zstd::compression_level_range();
+                Stream::Zstd(zstd::Encoder::new(writer, level.clamp(*range.start(), *range.end()))?)
+            },
+            Codec::Xz => Stream::Xz(XzEncoder::new(writer, level.clamp(0, 9) as u32)),
+            Codec::None => Stream::None(writer),
+        })
+    }
+
+    fn finish(self) -> io::Result<Counted> {
+        match self {
+            Stream::Gzip(encoder) => encoder.finish(),
+            Stream::Zstd(encoder) => encoder.finish(),
+            Stream::Xz(encoder) => encoder.finish(),
+            Stream::None(writer) => Ok(writer),
+        }
+    }
+}
+
+/// Compression of an archive being written. The compression stream is restarted by
+/// `restart` at the start of members, so they can be read without what comes before.
+pub struct Encoder {
+    stream: Option<Stream>,
+    codec: Codec,
+    level: i32,
+    frame: u64,   // offset of the current compression stream in the output
+    written: u64, // bytes written to the current compression stream
+}
+
+impl Encoder {
+    fn new(file: File, codec: Codec, level: Option<i32>) -> io::Result<Self> {
+        let writer = Counted { output: Output::new(file)?, written: 0 };
+        let level = level.unwrap_or(codec.default_level());
+
+        Ok(Encoder { stream: Some(Stream::new(writer, codec, level)?), codec, level, frame: 0, written: 0 })
+    }
+
+    fn stream(&mut self) -> &mut Stream {
+        self.stream.as_mut().expect("compression stream is already finished")
+    }
+
+    /// Where the next byte written goes, as (offset of its compression stream, offset in it)
+    fn position(&self) -> (u64, u64) {
+        (self.frame, self.written)
+    }
+
+    /// Completes the compression stream and starts a new one after it, once the current one
+    /// is larger than FRAME_SIZE. Uncompressed archives are read at any offset.
+    fn restart(&mut self) -> io::Result<()> {
+        if self.codec == Codec::None || self.written < FRAME_SIZE {
+            return Ok(());
+        }
+
+        let writer = self.stream.take().expect("compression stream is already finished").finish()?;
+        self.frame = writer.written;
+        self.written = 0;
+        self.stream = Some(Stream::new(writer, self.codec, self.level)?);
+
+        Ok(())
+    }
+
+    /// Completes the compression stream
+    fn finish(mut self) -> io::Result<BufWriter<File>> {
+        let writer = self.stream.take().expect("compression stream is already finished").finish()?;
+        writer.output.finish()
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match self.stream() {
+            Stream::Gzip(encoder) => encoder.write(buf),
+            Stream::Zstd(encoder) => encoder.write(buf),
+            Stream::Xz(encoder) => encoder.write(buf),
+            Stream::None(writer) => writer.write(buf),
+        }?;
+
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream() {
+            Stream::Gzip(encoder) => encoder.flush(),
+            Stream::Zstd(encoder) => encoder.flush(),
+            Stream::Xz(encoder) => encoder.flush(),
+            Stream::None(writer) => writer.flush(),
+        }
+    }
+}
+
+/// A compressed tar archive which members are streamed straight into,
+/// encrypted with the installed keys (see `crypto::install`).
+///
+/// The archive is written to a temp file next to the destination and renamed over it
+/// by `finish`, so a failed backup never leaves a partial archive in place of a snapshot.
+/// The temp file is removed if the writer is dropped before it is finished.
+///
+/// The files appended are indexed, see `MemberIndex`.
+pub struct ArchiveWriter {
+    builder: Option<Builder<Encoder>>,
+    index: MemberIndex,
+    temp_path: PathBuf,
+    destination: PathBuf,
+}
+
+impl ArchiveWriter {
+    /// level: compression level of codec, its default level if None
+    pub fn create(destination: &Path, codec: Codec, level: Option<i32>) -> io::Result<Self> {
+        let file_name = destination.file_name()
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Archive destination has no file name"))?;
+
+        // Unique per process, for concurrent backups to not collide
+        let temp_path = destination.with_file_name(format!(".{}.{}.tmp", file_name.to_string_lossy(), process::id()));
+
+        let file = File::create(&temp_path)?;
+        let encoder = match Encoder::new(file, codec, level) {
+            Ok(encoder) => encoder,
+            Err(err) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(err);
+            }
+        };
+
+        Ok(ArchiveWriter {
+            builder: Some(Builder::new(encoder)),
+            index: MemberIndex::default(),
+            temp_path,
+            destination: destination.to_path_buf(),
+        })
+    }
+
+    pub fn builder(&mut self) -> &mut Builder<Encoder> {
+        self.builder.as_mut().expect("archive is already finished")
+    }
+
+    /// Adds a directory (by member name) with the mode and mtime of the remote directory
+    pub fn append_dir(&mut self, name: &str, mode: u32, mtime: u64) -> io::Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(mode & 0o7777);
+        header.set_mtime(mtime);
+
+        self.builder().append_data(&mut header, format!("{}/", name.trim_end_matches('/')), io::empty())
+    }
+
+    /// Streams a file (by member name) from data into the archive, with the metadata of entry.
+    /// The header needs the size up front, so data is cut or zero-padded to exactly size bytes
+    /// if the file changed while it was read.
+    /// A read error also zero-pads the member, keeping the archive intact, and is returned
+    /// once the member is complete. A retry appends the file again, which wins on extraction.
+    pub fn append_file<R: Read>(&mut self, name: &str, entry: &FileEntry, size: u64, data: R) -> io::Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(size);
+        header.set_mode(if entry.mode == 0 { 0o644 } else { entry.mode & 0o7777 });
+        header.set_mtime(entry.mtime);
+        set_header_ownership(&mut header, entry)?;
+
+        let mut data = UntilError { inner: data, error: None };
+
+        let builder = self.builder();
+        builder.get_mut().restart()?;
+        let (frame, offset) = builder.get_ref().position();
+        self.index.members.insert(name.to_string(), MemberLocation { frame, offset, size });
+
+        let builder = self.builder();
+        append_pax_extensions(builder, name, entry)?;
+        builder.append_data(&mut header, name, (&mut data).take(size).chain(io::repeat(0)).take(size))?;
+
+        match data.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds links and special files as headers, see `append_special_entries`
+    pub fn append_special_entries(&mut self, special_entries: &[(&PathBuf, &FileEntry)]) -> io::Result<()> {
+        append_special_entries(self.builder(), special_entries)
+    }
+
+    /// Completes the compression stream, and moves the archive and its index into place
+    pub fn finish(mut self) -> io::Result<()> {
+        let builder = self.builder.take().expect("archive is already finished");
+
+        // The index first, so an archive in place always has its index
+        let result = builder.into_inner()
+            .and_then(|encoder| encoder.finish())
+            .and_then(|buf_writer| buf_writer.into_inner().map_err(|err| err.into_error()))
+            .and_then(|file| file.sync_all())
+            .and_then(|_| match self.index.members.is_empty() {
+                true => Ok(()),
+                false => self.index.write(&self.destination),
+            })
+            .and_then(|_| fs::rename(&self.temp_path, &self.destination));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+
+        result
+    }
+}
+
+/// Reader which ends at the first error of inner, keeping the error
+struct UntilError<R: Read> {
+    inner: R,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Read for UntilError<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.error.is_some() {
+            return Ok(0);
+        }
+
+        match self.inner.read(buf) {
+            Err(err) if err.kind() != io::ErrorKind::Interrupted => {
+                self.error = Some(err);
+                Ok(0)
+            },
+            result => result,
+        }
+    }
+}
+
+impl Drop for ArchiveWriter {
+    fn drop(&mut self) {
+        if self.builder.is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[test]
+fn test_archive_writer() {
+    let dir = std::env::temp_dir().join(format!("rensen-test-archive-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let destination = dir.join("snapshot.tar.gz");
+
+    let mut entry = FileEntry::new();
+    entry.mode = 0o600;
+    entry.mtime = 1700000000;
+
+    let mut archive = ArchiveWriter::create(&destination, Codec::Gzip, None).unwrap();
+    archive.append_dir("etc", 0o755, 1700000000).unwrap();
+    archive.append_file("etc/grown", &entry, 4, &b"grown since stat"[..]).unwrap();
+    archive.append_file("etc/shrunk", &entry, 4, &b"ab"[..]).unwrap();
+
+    // A failing read still completes the member
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("connection lost"))
+        }
+    }
+    assert!(archive.append_file("etc/failed", &entry, 2, (&b"a"[..]).chain(Failing)).is_err());
+    archive.append_file("etc/failed", &entry, 2, &b"ok"[..]).unwrap();
+
+    // Not in place until finished
+    assert!(!destination.exists());
+    archive.finish().unwrap();
+    assert!(destination.exists());
+
+    let mut tar = open_archive(&destination, Codec::Gzip).unwrap();
+    let members: Vec<(String, Vec<u8>)> = tar.entries().unwrap()
+        .map(|member| {
+            let mut member = member.unwrap();
+            let mut data = Vec::new();
+            member.read_to_end(&mut data).unwrap();
+            (member.path().unwrap().to_string_lossy().into_owned(), data)
+        })
+        .collect();
+
+    assert_eq!(members[0].0, "etc/");
+    assert_eq!(members[1], (String::from("etc/grown"), b"grow".to_vec()));
+    assert_eq!(members[2], (String::from("etc/shrunk"), b"ab\0\0".to_vec()));
+    assert_eq!(members[3], (String::from("etc/failed"), b"a\0".to_vec()));
+    assert_eq!(members[4], (String::from("etc/failed"), b"ok".to_vec()));
+
+    // Dropped unfinished, leaving nothing behind
+    let unfinished = dir.join("unfinished.tar.gz");
+    drop(ArchiveWriter::create(&unfinished, Codec::Gzip, None).unwrap());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2); // the archive and its index
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_codecs() {
+    let dir = std::env::temp_dir().join(format!("rensen-test-codecs-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let data = b"compressible ".repeat(1000);
+    for codec in [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::None] {
+        let destination = archive_path(&dir.join("snapshot"), codec);
+
+        // Out of range levels are clamped
+        let mut archive = ArchiveWriter::create(&destination, codec, Some(100)).unwrap();
+        archive.append_file("file", &FileEntry::new(), data.len() as u64, &data[..]).unwrap();
+        archive.finish().unwrap();
+
+        let mut member = Vec::new();
+        let mut tar = open_archive(&destination, codec).unwrap();
+        tar.entries().unwrap().next().unwrap().unwrap().read_to_end(&mut member).unwrap();
+        assert_eq!(member, data, "{}", codec);
+    }
+
+    assert!(dir.join("snapshot.tar.zst").exists());
+    assert!(fs::metadata(dir.join("snapshot.tar.xz")).unwrap().len() < fs::metadata(dir.join("snapshot.tar")).unwrap().len());
+
+    assert!(is_compressed_media(Path::new("/srv/photos/IMG_0001.JPG")));
+    assert!(!is_compressed_media(Path::new("/srv/db.sql")));
+    assert!(!is_compressed_media(Path::new("/srv/jpg")));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_copy_member() {
+    let dir = std::env::temp_dir().join(format!("rensen-test-member-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // Larger than a frame, so the members after it are in a compression stream of their own
+    let large = vec![7u8; FRAME_SIZE as usize + 1];
+    for codec in [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::None] {
+        let destination = archive_path(&dir.join("snapshot"), codec);
+
+        let mut archive = ArchiveWriter::create(&destination, codec, Some(1)).unwrap();
+        archive.append_file("etc/large", &FileEntry::new(), large.len() as u64, &large[..]).unwrap();
+        archive.append_file("etc/small", &FileEntry::new(), 5, &b"first"[..]).unwrap();
+        archive.append_file("etc/small", &FileEntry::new(), 5, &b"retry"[..]).unwrap();
+        archive.finish().unwrap();
+
+        let index = MemberIndex::load(&destination).unwrap().unwrap();
+        assert_eq!(index.members["etc/large"].frame, 0);
+        assert_eq!(index.members["etc/small"].frame > 0, codec != Codec::None, "{}", codec);
+
+        // The last of a member appended again
+        let mut out = Vec::new();
+        assert_eq!(copy_member(&destination, codec, Path::new("etc/small"), &mut out).unwrap(), Some(5));
+        assert_eq!(out, b"retry");
+        assert_eq!(copy_member(&destination, codec, Path::new("etc/missing"), &mut out).unwrap(), None);
+
+        // Still read as a whole, across compression streams
+        let members = open_archive(&destination, codec).unwrap().entries().unwrap().count();
+        assert_eq!(members, 3, "{}", codec);
+
+        // Without an index, by scanning, still the last of it
+        fs::remove_file(index_path(&destination)).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(copy_member(&destination, codec, Path::new("etc/small"), &mut out).unwrap(), Some(5));
+        assert_eq!(out, b"retry");
+        assert_eq!(copy_member(&destination, codec, Path::new("etc/missing"), &mut out).unwrap(), None);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,20 +1,49 @@
 pub mod rsync {
     use std::fs;
-    use std::io::{self, stdout, Write, Read};
-    use std::net::TcpStream;
-    use ssh2::{Session, FileStat};
-    use std::time::SystemTime;
+    use std::io::{self, stdout, Write, Read, Seek, SeekFrom};
+    use std::net::{TcpStream, ToSocketAddrs, SocketAddr};
+    use ssh2::{Session, FileStat, Channel};
+    use std::time::{SystemTime, Duration, Instant};
     use std::path::{Path, PathBuf}; 
     use std::ffi::OsStr;
     use console::Style;
-    use std::rc::Rc;
+    use std::sync::{Mutex, MutexGuard};
+    use std::thread;
+    use fxhash::FxHashMap;
+    use std::collections::hash_map::Entry;
+    use sha2::{Digest, Sha256};
+    use std::os::unix::fs::PermissionsExt;
 
     use crate::traits::*;
-    use crate::logging::Trap;
+    use crate::logging::{Trap, log_trap, log_message};
     use crate::config::*;
-    use crate::utils::{make_tar_gz, set_metadata, get_datetime, get_file_sz};
-    use crate::record::Record;
-    use crate::snapshot::{PathPair, FileEntry, Snapshot};
+    use crate::utils::{get_datetime, source_subtree, shell_quote};
+    use crate::utils::{read_block, group_hardlinks};
+    use crate::utils::{parse_id_names, parse_getfattr, IdNames, expand_home, backoff_delay, lock_host};
+    use crate::record::{Record, BackupKind};
+    use crate::snapshot::{FileEntry, EntryKind};
+    use crate::filter::PathFilter;
+    use crate::tunnel::forward;
+    use crate::crypto;
+    use crate::chunks::{ChunkStore, chunk_root};
+    use crate::delta::{parse_signatures, match_blocks};
+    use crate::prune::prune_locked;
+    use crate::archive::{ArchiveWriter, Codec, archive_path, is_compressed_media, copy_member};
+    use crate::known_hosts::{known_hosts_path, known_host_name, check_host_key, add_host_key, fingerprint, HostKeyCheck};
+
+    // File type bits of FileStat::perm
+    const S_IFMT: u32 = 0o170000;
+    const S_IFREG: u32 = 0o100000;
+    const S_IFLNK: u32 = 0o120000;
+    const S_IFIFO: u32 = 0o010000;
+    const S_IFCHR: u32 = 0o020000;
+    const S_IFBLK: u32 = 0o060000;
+
+    /// Virtual remote directory the output of command sources is placed in
+    pub const COMMAND_ROOT: &str = "/.commands";
+
+    /// Files up to this size are read into memory before they are added to the archive, larger ones are spooled to disk
+    const SPOOL_IN_MEMORY: u64 = 8 * 1024 * 1024;
 
     pub struct Sftp<'a> {
         
@@ -22,15 +51,30 @@ pub mod rsync {
         pub host_config: &'a HostConfig,
         pub global_config: &'a GlobalConfig,
         pub record: Record,
-        pub sess: Option<Session>,
+        pub sess: Option<Mutex<Session>>,
         pub incremental: bool,
+        pub differential: bool, // copy everything changed since the last full backup
         pub debug: bool,
 
         /* Private */
         host_root_path: Option<PathBuf>,
         snapshot_root_path: Option<PathBuf>,
-        complete_destination: Option<PathBuf>,
-        style: Rc<Style>,
+        archives: Mutex<FxHashMap<Codec, ArchiveWriter>>,
+        chunk_store: Option<ChunkStore>, // files go into the chunk store instead of archives (dedup storage)
+        filter: Option<PathFilter>,
+        remote_entries: Mutex<FxHashMap<PathBuf, FileEntry>>,
+        special_entries: Mutex<Vec<(PathBuf, FileEntry)>>,
+        hardlinks: FxHashMap<PathBuf, PathBuf>,
+        users: IdNames,
+        groups: IdNames,
+        style: Style,
+    }
+
+    /// A remote file which is to be copied to destination (its path within the snapshot)
+    pub struct RemoteFile {
+        pub source: PathBuf,
+        pub destination: PathBuf,
+        pub stat: FileStat,
     }
 
     impl<'a> Sftp<'a> {
@@ -41,12 +85,20 @@ pub mod rsync {
                 record,
                 sess: None,
                 incremental: false,
+                differential: false,
                 debug,
 
                 host_root_path: None,
                 snapshot_root_path: None,
-                complete_destination: None,
-                style: Rc::new(Style::new()),
+                archives: Mutex::new(FxHashMap::default()),
+                chunk_store: None,
+                filter: None,
+                remote_entries: Mutex::new(FxHashMap::default()),
+                special_entries: Mutex::new(Vec::new()),
+                hardlinks: FxHashMap::default(),
+                users: FxHashMap::default(),
+                groups: FxHashMap::default(),
+                style: Style::new(),
             }
         }
 
@@ -63,109 +115,1128 @@ pub mod rsync {
             Ok(())
         }
 
-        /// Returns last_modified_time from metadata in secs (as u64)
-        pub fn local_file_mtime(&self, local_file: &Path) -> Result<u64, Trap> {
-            let local_metadata = fs::metadata(local_file).map_err(|err| {
-                Trap::Metadata(
-                    format!("Could not get metadata of local file: {}.\nMay be missing or corrupt!", err))
+        /// Returns last_modified_time from metadata in secs (as u64)
+        pub fn local_file_mtime(&self, local_file: &Path) -> Result<u64, Trap> {
+            let local_metadata = fs::metadata(local_file).map_err(|err| {
+                Trap::Metadata(
+                    format!("Could not get metadata of local file: {}.\nMay be missing or corrupt!", err))
+            })?;
+
+            let local_modified = local_metadata.modified().map_err(|err| {
+                Trap::Metadata(format!("Could not get mod time of local file: {}", err))
+            })?;
+
+            Ok(local_modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs())
+        }
+
+        /// Wrapper for SFTP::stat
+        pub fn remote_filestat(&self, remote_file: &Path) -> Result<FileStat, Trap> {
+            let sftp = self.session()?.sftp().map_err(|err| {
+                Trap::Session(format!("Could not init SFTP session: {}", err))
+            })?;
+
+            let stat = sftp.stat(remote_file).map_err(|err| {
+                Trap::Metadata(
+                    format!("Could not get metadata of remote file: {}\nMay be missing or corrupt!", err))
+            })?;
+
+            Ok(stat)
+        }
+
+        /// Wrapper for SFTP::lstat, not following symlinks
+        pub fn remote_file_lstat(&self, remote_file: &Path) -> Result<FileStat, Trap> {
+            let sftp = self.session()?.sftp().map_err(|err| {
+                Trap::Session(format!("Could not init SFTP session: {}", err))
+            })?;
+
+            sftp.lstat(remote_file).map_err(|err| {
+                Trap::Metadata(format!("Could not get metadata of remote file: {}", err))
+            })
+        }
+
+        /// Locks and returns the main session
+        pub fn session(&self) -> Result<MutexGuard<'_, Session>, Trap> {
+            self.sess.as_ref()
+                .ok_or(Trap::Session(String::from("Session unavailable")))?
+                .lock()
+                .map_err(|err| Trap::Session(format!("Session is poisoned: {}", err)))
+        }
+
+        /// Runs f with the archive of the snapshot being taken which is compressed with codec,
+        /// creating it on first use. Workers share the archives, so they are locked while f runs.
+        fn with_archive<T>(&self, codec: Codec, f: impl FnOnce(&mut ArchiveWriter) -> Result<T, Trap>) -> Result<T, Trap> {
+            let mut archives = self.archives.lock()
+                .map_err(|err| Trap::FS(format!("Archives are poisoned: {}", err)))?;
+
+            let archive = match archives.entry(codec) {
+                Entry::Occupied(archive) => archive.into_mut(),
+                Entry::Vacant(vacant) => {
+                    let snapshot_root_path = self.snapshot_root_path.as_ref()
+                        .ok_or(Trap::Missing(String::from("Snapshot root path is not set")))?;
+
+                    let path = archive_path(snapshot_root_path, codec);
+                    let archive = ArchiveWriter::create(&path, codec, self.host_config.compression_level).map_err(|err| {
+                        Trap::FS(format!("Could not create archive {:?}: {}", path, err))
+                    })?;
+
+                    vacant.insert(archive)
+                },
+            };
+
+            f(archive)
+        }
+
+        /// Codec of the archive a file goes into. Files which are compressed already go
+        /// into the uncompressed archive of the snapshot if the host stores them as they are.
+        fn file_codec(&self, source: &Path) -> Codec {
+            if self.host_config.store_compressed_media.unwrap_or(false) && is_compressed_media(source) {
+                return Codec::None;
+            }
+
+            self.host_config.compression.unwrap_or_default()
+        }
+
+        /// Name of the archive member of destination (a path within the snapshot)
+        fn member_name(&self, destination: &Path) -> Result<String, Trap> {
+            let snapshot_root_path = self.snapshot_root_path.as_ref()
+                .ok_or(Trap::Missing(String::from("Snapshot root path is not set")))?;
+
+            let member = destination.strip_prefix(snapshot_root_path).map_err(|err| {
+                Trap::Missing(format!("{:?} is not within the snapshot: {}", destination, err))
+            })?;
+
+            Ok(member.to_string_lossy().into_owned())
+        }
+
+        /// Adds the remote directory (by its destination) to the archive.
+        /// Deduplicated snapshots have no archive, their directories are implied by the files.
+        fn append_dir(&self, destination: &Path, stat: &FileStat) -> Result<(), Trap> {
+            if self.chunk_store.is_some() {
+                return Ok(());
+            }
+
+            let member = self.member_name(destination)?;
+            self.with_archive(self.host_config.compression.unwrap_or_default(), |archive| {
+                archive.append_dir(&member, stat.perm.unwrap_or(0o755), stat.mtime.unwrap_or(0)).map_err(|err| {
+                    Trap::FS(format!("Could not add directory {} to archive: {}", member, err))
+                })
+            })
+        }
+
+        /// Stores size bytes of data as the file at destination: in the chunk store if the host
+        /// deduplicates, setting the chunks of entry, otherwise in the archive of entry's codec.
+        fn store_file<R: Read>(&self, destination: &Path, entry: &mut FileEntry, size: u64, data: R) -> Result<(), Trap> {
+            let member = self.member_name(destination)?;
+
+            match &self.chunk_store {
+                Some(chunk_store) => {
+                    let (chunks, stored) = chunk_store.store(data.take(size))?;
+                    if stored != size {
+                        return Err(Trap::Copy(format!("{} is truncated, got {} of {} bytes", member, stored, size)));
+                    }
+
+                    entry.chunks = Some(chunks);
+                    Ok(())
+                },
+                None => self.with_archive(entry.codec, |archive| {
+                    archive.append_file(&member, entry, size, data).map_err(|err| {
+                        Trap::Copy(format!("Could not add {} to archive: {}", member, err))
+                    })
+                }),
+            }
+        }
+
+        /// Stores a local file (delta patched or command output) at destination (see `store_file`).
+        /// Returns the size of the file.
+        fn append_local_file(&self, destination: &Path, entry: &mut FileEntry, local_path: &Path) -> Result<u64, Trap> {
+            let file_handle = fs::File::open(local_path).map_err(|err| {
+                Trap::FS(format!("Could not open {:?}: {}", local_path, err))
+            })?;
+
+            let size = file_handle.metadata().map_err(|err| {
+                Trap::Metadata(format!("Could not get metadata of {:?}: {}", local_path, err))
+            })?.len();
+
+            self.store_file(destination, entry, size, file_handle)?;
+
+            Ok(size)
+        }
+
+        /// Reads size bytes of data, outside of the archive lock, and stores them as the file at destination
+        /// (see `store_file`). Small files are buffered in memory, larger ones in a spool file of destination.
+        fn store_spooled<R: Read>(&self, destination: &Path, entry: &mut FileEntry, size: u64, data: R) -> Result<(), Trap> {
+            if size <= SPOOL_IN_MEMORY {
+                let mut buffer = Vec::with_capacity(size as usize);
+                data.take(size).read_to_end(&mut buffer).map_err(|err| {
+                    Trap::Copy(format!("Could not read {:?}: {}", destination, err))
+                })?;
+
+                if buffer.len() as u64 != size {
+                    return Err(Trap::Copy(format!("{:?} is truncated, got {} of {} bytes", destination, buffer.len(), size)));
+                }
+
+                return self.store_file(destination, entry, size, &buffer[..]);
+            }
+
+            let spool_path = self.scratch_path(destination, "spool")?;
+            let spooled = fs::File::create(&spool_path)
+                .and_then(|mut spool| io::copy(&mut data.take(size), &mut spool))
+                .map_err(|err| Trap::Copy(format!("Could not spool {:?} to {:?}: {}", destination, spool_path, err)))
+                .and_then(|spooled| match spooled == size {
+                    true => self.append_local_file(destination, entry, &spool_path).map(|_| ()),
+                    false => Err(Trap::Copy(format!("{:?} is truncated, got {} of {} bytes", destination, spooled, size))),
+                });
+
+            let _ = fs::remove_file(&spool_path);
+            spooled
+        }
+
+        /// Path for a working file of destination (delta base, output of a command).
+        /// Working files live in the snapshot directory, which is removed once the archive is finished.
+        fn scratch_path(&self, destination: &Path, suffix: &str) -> Result<PathBuf, Trap> {
+            let scratch_path = PathBuf::from(format!("{}.{}", destination.display(), suffix));
+
+            if let Some(parent) = scratch_path.parent() {
+                fs::create_dir_all(parent).map_err(|err| {
+                    Trap::FS(format!("Could not create directory: {}", err))
+                })?;
+            }
+
+            Ok(scratch_path)
+        }
+
+        /// Opens a session to the host and verifies its host key against the known hosts.
+        fn open_session(&self) -> Result<Session, Trap> {
+            let sess = self.handshake()?;
+            self.verify_host_key(&sess, &self.host_config.identifier, self.host_config.port.unwrap_or(22))?;
+
+            Ok(sess)
+        }
+
+        /// Verifies the host key of sess (identifier:port) against the known hosts.
+        /// Unknown hosts are trusted on first use, a changed host key is a Trap::HostKey.
+        fn verify_host_key(&self, sess: &Session, identifier: &str, port: u16) -> Result<(), Trap> {
+            let known_hosts = known_hosts_path(self.global_config);
+
+            if check_host_key(sess, identifier, port, &known_hosts)? == HostKeyCheck::NotFound {
+                add_host_key(sess, identifier, port, &known_hosts)?;
+                println!("{} Permanently added {} ({}) to {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Trusting")), known_host_name(identifier, port), fingerprint(sess).unwrap_or_default(), known_hosts);
+            }
+
+            Ok(())
+        }
+
+        /// Connects to the host and performs the SSH handshake, without verifying the host key.
+        /// Hosts behind jump hosts are connected to through a tunnel (see `tunnel`).
+        pub fn handshake(&self) -> Result<Session, Trap> {
+            let identifier = &self.host_config.identifier;
+            let port = self.host_config.port.unwrap_or(22);
+
+            let tcp = match &self.host_config.proxy_jump {
+                Some(jump_hosts) if !jump_hosts.is_empty() => {
+                    let local_addr = self.tunnel(jump_hosts, identifier, port)?;
+                    self.connect_tcp(local_addr)?
+                },
+                _ => self.connect_tcp((identifier.as_str(), port))?,
+            };
+
+            self.handshake_tcp(tcp)
+        }
+
+        /// Opens a TCP connection, trying every address addr resolves to
+        fn connect_tcp<A: ToSocketAddrs>(&self, addr: A) -> Result<TcpStream, Trap> {
+            let connect_timeout = Duration::from_secs(self.host_config.connect_timeout.unwrap_or(30));
+
+            let addrs = addr.to_socket_addrs().map_err(|err| {
+                Trap::Connect(format!("Could not resolve host: {}\nHost unreachable!", err))
+            })?;
+
+            let mut tcp = Err(io::Error::new(io::ErrorKind::NotFound, "no addresses"));
+            for addr in addrs {
+                tcp = TcpStream::connect_timeout(&addr, connect_timeout);
+                if tcp.is_ok() {
+                    break;
+                }
+            }
+
+            tcp.map_err(|err| {
+                Trap::Connect(format!("Could not connect to host: {}\nHost unreachable!", err))
+            })
+        }
+
+        /// Performs the SSH handshake over tcp.
+        /// The session gets the timeout and keepalive interval of the host.
+        fn handshake_tcp(&self, tcp: TcpStream) -> Result<Session, Trap> {
+            // Create SSH session
+            let mut sess = Session::new().map_err(|err| {
+                Trap::Session(format!("Could not create SSH session: {}", err))
+
+            })?;
+
+            // Blocking calls fail after the timeout instead of hanging on a dropped host
+            let timeout = self.host_config.timeout.unwrap_or(300);
+            sess.set_timeout((timeout * 1000).min(u32::MAX as u64) as u32);
+
+            // Perform SSH handshake
+            sess.set_tcp_stream(tcp);
+            sess.handshake().map_err(|err| {
+                Trap::Handshake(format!("Could not perform SSH handshake: {}", err))
+            })?;
+
+            let keepalive_interval = self.host_config.keepalive_interval.unwrap_or(60);
+            sess.set_keepalive(keepalive_interval > 0, keepalive_interval);
+
+            Ok(sess)
+        }
+
+        /// Tunnels to identifier:port through the chain of jump hosts, every jump host
+        /// being reached through the one before it. Returns the local address the
+        /// host is forwarded to.
+        fn tunnel(&self, jump_hosts: &[JumpHost], identifier: &str, port: u16) -> Result<SocketAddr, Trap> {
+            let mut local_addr: Option<SocketAddr> = None;
+
+            for (i, jump_host) in jump_hosts.iter().enumerate() {
+                let jump_port = jump_host.port.unwrap_or(22);
+                let _ = self.debug(&format!("Jumping through {}... ", jump_host));
+
+                let tcp = match local_addr {
+                    Some(local_addr) => self.connect_tcp(local_addr)?,
+                    None => self.connect_tcp((jump_host.identifier.as_str(), jump_port))?,
+                };
+
+                let sess = self.handshake_tcp(tcp)?;
+                self.verify_host_key(&sess, &jump_host.identifier, jump_port)?;
+                authenticate(&sess, &jump_host.user, jump_host.auth, jump_host.key.as_ref(), jump_host.secret.as_ref())?;
+
+                let (next_identifier, next_port) = match jump_hosts.get(i + 1) {
+                    Some(next) => (next.identifier.as_str(), next.port.unwrap_or(22)),
+                    None => (identifier, port),
+                };
+
+                local_addr = Some(forward(sess, next_identifier, next_port)?);
+                let _ = self.debug("Done\n");
+            }
+
+            local_addr.ok_or(Trap::Config(String::from("No jump hosts to tunnel through")))
+        }
+
+        /// Runs attempt until it succeeds, retrying transient connection errors
+        /// (Trap::Connect and Trap::Handshake) with exponential backoff.
+        fn with_retries<T>(&self, mut attempt: impl FnMut() -> Result<T, Trap>) -> Result<T, Trap> {
+            let retries = self.host_config.retries.unwrap_or(3);
+            let retry_delay = Duration::from_secs(self.host_config.retry_delay.unwrap_or(2));
+            let mut tries = 0;
+
+            loop {
+                match attempt() {
+                    Err(err @ (Trap::Connect(_) | Trap::Handshake(_))) if tries < retries => {
+                        let delay = backoff_delay(retry_delay, tries);
+                        println!("{} in {:?} ({}/{}): {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Retrying")), delay, tries + 1, retries, err);
+                        thread::sleep(delay);
+                        tries += 1;
+                    },
+                    result => return result,
+                }
+            }
+        }
+
+        /// Opens and authenticates a new session, retrying connection errors
+        fn reconnect(&self) -> Result<Session, Trap> {
+            self.with_retries(|| {
+                let sess = self.open_session()?;
+                self.auth_session(&sess)?;
+                Ok(sess)
+            })
+        }
+
+        /// Checks if sess is still connected, by running a no-op on the host
+        fn is_alive(&self, sess: &Session) -> bool {
+            self.remote_exec(sess, "true").is_ok()
+        }
+
+        /// Authenticates sess according to the auth method of the host
+        fn auth_session(&self, sess: &Session) -> Result<(), Trap> {
+            let host_config = self.host_config;
+            authenticate(sess, &host_config.user, host_config.auth, host_config.key.as_ref(), host_config.secret.as_ref())
+        }
+
+        /// Finds the regular files under source_root which share their inode with other files
+        /// (GNU find), mapping each of them to the first file of the inode.
+        /// Excluded files are left out, so that a link never points to a file which is not backed up.
+        fn find_hardlinks(&self, sess: &Session, source_root: &Path) -> Result<FxHashMap<PathBuf, PathBuf>, Trap> {
+            let output = self.remote_exec(sess, &format!("find {} -type f -links +1 -printf '%D:%i %p\\0'", shell_quote(source_root)))?;
+
+            let links = output.split('\0')
+                .filter_map(|line| line.split_once(' '))
+                .map(|(inode, path)| (inode.to_string(), PathBuf::from(path)))
+                .filter(|(_, path)| !self.is_excluded(path, false))
+                .collect();
+
+            Ok(group_hardlinks(links))
+        }
+
+        /// Builds the entry for a link or special file, which is only kept as metadata.
+        fn special_entry(&self, sess: &Session, sftp: &ssh2::Sftp, source: &Path, stat: &FileStat, kind: EntryKind) -> Result<FileEntry, Trap> {
+            let mut entry = FileEntry::new();
+            entry.kind = kind;
+            entry.mtime = stat.mtime.unwrap_or(0);
+            entry.mode = stat.perm.unwrap_or(0) & 0o7777;
+            self.set_ownership(sess, source, stat, &mut entry)?;
+
+            match kind {
+                EntryKind::Symlink => {
+                    entry.link_target = Some(sftp.readlink(source).map_err(|err| {
+                        Trap::Metadata(format!("Could not read link {:?}: {}", source, err))
+                    })?);
+                },
+                EntryKind::HardLink => {
+                    entry.link_target = self.hardlinks.get(source).cloned();
+                },
+                EntryKind::CharDevice | EntryKind::BlockDevice => {
+                    let output = self.remote_exec(sess, &format!("stat -c '%t %T' -- {}", shell_quote(source)))?;
+                    let mut fields = output.split_whitespace().map(|field| u32::from_str_radix(field, 16));
+
+                    match (fields.next(), fields.next()) {
+                        (Some(Ok(major)), Some(Ok(minor))) => entry.device = Some((major, minor)),
+                        _ => return Err(Trap::Metadata(format!("Unexpected output from stat: `{}`", output.trim()))),
+                    }
+                },
+                _ => (),
+            }
+
+            Ok(entry)
+        }
+
+        /// Recurses the remote directory (source) and adds the equivalent directories to the archive.
+        /// Files that are to be copied are collected into files, while links and special files
+        /// are recorded as metadata.
+        fn collect_remote_files(&self, sess: &Session, sftp: &ssh2::Sftp, source: &Path, destination: &Path, files: &mut Vec<RemoteFile>) -> Result<(), Trap> {
+            let dir_entries = sftp.readdir(source).map_err(|err| {
+                Trap::Copy(format!("Could not read remote directory: {}", err))
+
+            })?;
+
+            for (entry, stat) in dir_entries {
+                let entryname = match entry.file_name() {
+                    Some(entryname) => {
+                        entryname 
+                    },
+                    None => {
+                        continue;
+                    },
+                };
+
+                // format paths
+                let new_source = source.join(entryname);
+                let new_destination = destination.join(entryname);
+
+                if self.is_excluded(&new_source, stat.is_dir()) {
+                    let _ = self.debug(format!("{} {:?}\n", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Excluding")), &new_source).as_str());
+                    continue;
+                }
+
+                if stat.is_file() && !self.hardlinks.contains_key(&new_source) {
+                    files.push(RemoteFile { source: new_source, destination: new_destination, stat });
+                }
+                else if stat.is_dir() {
+                    self.append_dir(&new_destination, &stat)?;
+                    match self.collect_remote_files(sess, sftp, &new_source, &new_destination, files) {
+                        Ok(_) => (),
+                        Err(err) => { 
+                            println!("{} Directory out of reach, please check permissions: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
+                        }
+                    }
+                }
+                else {
+                    let kind = match stat.perm.unwrap_or(0) & S_IFMT {
+                        S_IFREG => EntryKind::HardLink,
+                        S_IFLNK => EntryKind::Symlink,
+                        S_IFIFO => EntryKind::Fifo,
+                        S_IFCHR => EntryKind::CharDevice,
+                        S_IFBLK => EntryKind::BlockDevice,
+                        _ => {
+                            let _ = self.debug(format!("{} {:?} (unsupported file type)\n", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Ignoring")), &new_source).as_str());
+                            continue;
+                        }
+                    };
+
+                    match self.special_entry(sess, sftp, &new_source, &stat, kind) {
+                        Ok(entry) => {
+                            println!("{} {}@{}:{:?} ({:?})", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Recording")), self.host_config.user, self.host_config.identifier, &new_source, kind);
+                            if let Ok(mut special_entries) = self.special_entries.lock() {
+                                special_entries.push((new_source, entry));
+                            }
+                        },
+                        Err(err) => {
+                            println!("{} {:?} Could not record entry: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), new_source, err);
+                        }
+                    }
+                }
+            }
+           
+            Ok(())
+        }
+
+        /// Copies the collected files. If the host has a parallelism above 1,
+        /// that many worker sessions will be opened, each copying a file at a time
+        /// from the shared queue.
+        fn copy_remote_files(&self, mut files: Vec<RemoteFile>) -> Result<(), Trap> {
+            let parallelism = self.host_config.parallelism.unwrap_or(1).max(1).min(files.len().max(1));
+
+            if parallelism == 1 {
+                let mut session = self.session()?;
+                for file in files.iter() {
+                    self.copy_and_report(&mut session, file)?;
+                }
+
+                return Ok(());
+            }
+
+            // Popping from the back, so reversing to keep the order of the walk
+            files.reverse();
+            let queue = Mutex::new(files);
+
+            thread::scope(|scope| {
+                for _ in 0..parallelism {
+                    scope.spawn(|| {
+                        // Every worker has its own session
+                        let mut sess = match self.reconnect() {
+                            Ok(sess) => sess,
+                            Err(err) => {
+                                println!("{} Could not open worker session: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Worker")), err);
+                                return;
+                            }
+                        };
+
+                        loop {
+                            let file = match queue.lock() {
+                                Ok(mut queue) => queue.pop(),
+                                Err(_) => None,
+                            };
+
+                            let file = match file {
+                                Some(file) => file,
+                                None => break,
+                            };
+
+                            // Lost the host, leaving the file to the main session
+                            if let Err(err) = self.copy_and_report(&mut sess, &file) {
+                                println!("{} Could not reconnect worker session: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Worker")), err);
+                                if let Ok(mut queue) = queue.lock() {
+                                    queue.push(file);
+                                }
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+
+            // Files left behind if worker sessions could not be opened or were lost
+            let remaining = queue.into_inner().unwrap_or_default();
+            if !remaining.is_empty() {
+                let mut session = self.session()?;
+                for file in remaining.iter().rev() {
+                    self.copy_and_report(&mut session, file)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Copies file with sess and reports the outcome. If the session was dropped,
+        /// it is reconnected and the copy resumed at this file.
+        /// Only failing to reconnect is returned as an error.
+        fn copy_and_report(&self, sess: &mut Session, file: &RemoteFile) -> Result<(), Trap> {
+            let _ = sess.keepalive_send();
+
+            let mut result = self.copy_remote_file_with(sess, file);
+            if result.is_err() && !self.is_alive(sess) {
+                println!("{} Session dropped while copying {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Reconnecting")), file.source);
+                *sess = self.reconnect()?;
+                result = self.copy_remote_file_with(sess, file);
+            }
+
+            self.report_copy(file, result);
+            Ok(())
+        }
+
+        /// Reports the outcome of copying a single file
+        fn report_copy(&self, file: &RemoteFile, result: Result<(), Trap>) {
+            if let Err(err) = result {
+                println!("{} {:?} Could not receive file, please check permissions: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), file.source, err);
+            }
+        }
+
+        /// Executes command on the remote host over an SSH exec channel,
+        /// returning stdout. Non-zero exit status is returned as Trap::Channel.
+        pub fn remote_exec(&self, sess: &Session, command: &str) -> Result<String, Trap> {
+            let mut channel = sess.channel_session().map_err(|err| {
+                Trap::Channel(format!("Could not open channel: {}", err))
+            })?;
+
+            channel.exec(command).map_err(|err| {
+                Trap::Channel(format!("Could not execute `{}`: {}", command, err))
+            })?;
+
+            let mut stdout = String::new();
+            channel.read_to_string(&mut stdout).map_err(|err| {
+                Trap::Channel(format!("Could not read output of `{}`: {}", command, err))
+            })?;
+
+            let mut stderr = String::new();
+            let _ = channel.stderr().read_to_string(&mut stderr);
+            let _ = channel.wait_close();
+
+            match channel.exit_status() {
+                Ok(0) => Ok(stdout),
+                Ok(status) => Err(Trap::Channel(format!("`{}` exited with status {}: {}", command, status, stderr.trim()))),
+                Err(err) => Err(Trap::Channel(format!("Could not get exit status of `{}`: {}", command, err))),
+            }
+        }
+
+        /// Runs the hook commands (stage: pre_backup or post_backup) on the host in order.
+        /// Their output is written to the backup log, a failing command stops the rest with a Trap::Hook.
+        fn run_hooks(&self, stage: &str, commands: &[String]) -> Result<(), Trap> {
+            let timeout = Duration::from_secs(self.host_config.hook_timeout.unwrap_or(600));
+            let session = self.session()?;
+
+            for command in commands {
+                println!("{} {} `{}`", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Running")), stage, command);
+
+                let (status, stdout, stderr) = self.exec_with_timeout(&session, command, timeout)
+                    .map_err(|err| Trap::Hook(format!("{} `{}` failed: {:?}", stage, command, err)))?;
+
+                log_message(self.global_config, &format!(
+                    "Hook: {} `{}` on {} exited with status {}\nstdout:\n{}\nstderr:\n{}",
+                    stage, command, self.host_config.identifier, status, stdout.trim_end(), stderr.trim_end()
+                ));
+
+                if status != 0 {
+                    return Err(Trap::Hook(format!("{} `{}` exited with status {}: {}", stage, command, status, stderr.trim())));
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Executes command over an exec channel, giving up after timeout.
+        /// Returns the exit status, stdout and stderr.
+        fn exec_with_timeout(&self, sess: &Session, command: &str, timeout: Duration) -> Result<(i32, String, String), Trap> {
+            let mut channel = sess.channel_session().map_err(|err| {
+                Trap::Channel(format!("Could not open channel: {}", err))
+            })?;
+
+            channel.exec(command).map_err(|err| {
+                Trap::Channel(format!("Could not execute `{}`: {}", command, err))
+            })?;
+
+            // Non-blocking, to keep track of the time while reading both stdout and stderr
+            sess.set_blocking(false);
+            let output = read_channel_until(&mut channel, Instant::now() + timeout);
+            sess.set_blocking(true);
+
+            let (stdout, stderr) = match output {
+                Ok(output) => output,
+                Err(err) => {
+                    let _ = channel.close();
+                    return Err(err);
+                }
+            };
+
+            let _ = channel.wait_close();
+            let status = channel.exit_status().map_err(|err| {
+                Trap::Channel(format!("Could not get exit status of `{}`: {}", command, err))
+            })?;
+
+            Ok((status, String::from_utf8_lossy(&stdout).into_owned(), String::from_utf8_lossy(&stderr).into_owned()))
+        }
+
+        /// Streams the stdout of a command source into the snapshot as the file
+        /// `COMMAND_ROOT/<name>`, recording its size and checksum.
+        fn copy_command_output(&self, command_source: &CommandSource) -> Result<(), Trap> {
+            let name = Path::new(&command_source.name);
+            if name.file_name() != Some(name.as_os_str()) {
+                return Err(Trap::Config(format!("Invalid name of command source: `{}`", command_source.name)));
+            }
+
+            let source = Path::new(COMMAND_ROOT).join(name);
+            let snapshot_root_path = self.snapshot_root_path.as_ref()
+                .ok_or(Trap::Missing(String::from("Snapshot root path is not set")))?;
+            let destination = source_subtree(snapshot_root_path, &source);
+
+            // The size of the output is not known up front, which the archive header needs
+            let output_path = self.scratch_path(&destination, "output")?;
+
+            println!("{} {}@{}:`{}`", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Dumping")), self.host_config.user, self.host_config.identifier, command_source.command);
+
+            let session = self.session()?;
+            let mut channel = session.channel_session().map_err(|err| {
+                Trap::Channel(format!("Could not open channel: {}", err))
+            })?;
+
+            channel.exec(&command_source.command).map_err(|err| {
+                Trap::Channel(format!("Could not execute `{}`: {}", command_source.command, err))
+            })?;
+
+            let mut file_handle = fs::File::create(&output_path).map_err(|err| {
+                Trap::FS(format!("Could not create file {:?}: {}", output_path, err))
+            })?;
+            let _ = file_handle.set_permissions(fs::Permissions::from_mode(0o600));
+
+            // Hashing while writing, to not read the output twice
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0; 64 * 1024];
+            let mut size = 0;
+
+            loop {
+                let bytes_read = channel.read(&mut buffer).map_err(|err| {
+                    Trap::Channel(format!("Could not read output of `{}`: {}", command_source.command, err))
+                })?;
+
+                if bytes_read == 0 {
+                    break;
+                }
+
+                file_handle.write_all(&buffer[..bytes_read]).map_err(|err| {
+                    Trap::FS(format!("Could not write to {:?}: {}", output_path, err))
+                })?;
+                hasher.update(&buffer[..bytes_read]);
+                size += bytes_read as u64;
+            }
+
+            let mut stderr = String::new();
+            let _ = channel.stderr().read_to_string(&mut stderr);
+            let _ = channel.wait_close();
+
+            match channel.exit_status() {
+                Ok(0) => (),
+                status => {
+                    let _ = fs::remove_file(&output_path);
+                    return Err(Trap::Channel(format!("`{}` failed ({:?}): {}", command_source.command, status, stderr.trim())));
+                }
+            }
+
+            let mut entry = FileEntry::new();
+            entry.size = size;
+            entry.mode = 0o600;
+            entry.mtime = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+            entry.checksum = Some(format!("{:x}", hasher.finalize()));
+            entry.command = Some(command_source.command.clone());
+            entry.codec = self.host_config.compression.unwrap_or_default();
+
+            let appended = self.append_local_file(&destination, &mut entry, &output_path);
+            let _ = fs::remove_file(&output_path);
+            appended?;
+
+            // Kept until the record is updated
+            if let Ok(mut remote_entries) = self.remote_entries.lock() {
+                remote_entries.insert(source, entry);
+            }
+
+            Ok(())
+        }
+
+        /// Fetches the user and group names of the remote host (id: name),
+        /// used to record the owner of every entry by name as well.
+        fn find_owners(&self, sess: &Session) -> Result<(IdNames, IdNames), Trap> {
+            let users = self.remote_exec(sess, "getent passwd")?;
+            let groups = self.remote_exec(sess, "getent group")?;
+
+            Ok((parse_id_names(&users), parse_id_names(&groups)))
+        }
+
+        /// Records the ownership of the remote file (source) in entry, along with
+        /// its xattrs (getfattr) and ACL (getfacl) if enabled for the host.
+        fn set_ownership(&self, sess: &Session, source: &Path, stat: &FileStat, entry: &mut FileEntry) -> Result<(), Trap> {
+            entry.uid = stat.uid;
+            entry.gid = stat.gid;
+            entry.owner = stat.uid.and_then(|uid| self.users.get(&uid).cloned());
+            entry.group = stat.gid.and_then(|gid| self.groups.get(&gid).cloned());
+
+            if self.host_config.xattrs.unwrap_or(false) {
+                let output = self.remote_exec(sess, &format!("getfattr -h -d -m - -e hex -- {}", shell_quote(source)))?;
+                let xattrs = parse_getfattr(&output);
+                entry.xattrs = if xattrs.is_empty() { None } else { Some(xattrs) };
+            }
+
+            // ACLs of symlinks are those of what they point to
+            if self.host_config.acls.unwrap_or(false) && entry.kind != EntryKind::Symlink {
+                let output = self.remote_exec(sess, &format!("getfacl -c -s -E -- {}", shell_quote(source)))?;
+                let acl: Vec<&str> = output.lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("default:"))
+                    .collect();
+
+                entry.acl = if acl.is_empty() { None } else { Some(acl.join(",")) };
+            }
+
+            Ok(())
+        }
+
+        /// Builds the entry describing the current remote state of file.
+        /// ctime and inode (GNU stat) or the checksum (sha256sum) are only
+        /// fetched from the remote host if the change detection strategy needs them.
+        fn remote_entry(&self, sess: &Session, file: &RemoteFile, strategy: ChangeDetection) -> Result<FileEntry, Trap> {
+            let mut entry = FileEntry::new();
+            entry.mtime = file.stat.mtime.unwrap_or(u64::MAX);
+            entry.size = file.stat.size.unwrap_or(0);
+            entry.mode = file.stat.perm.unwrap_or(0) & 0o7777;
+            self.set_ownership(sess, &file.source, &file.stat, &mut entry)?;
+
+            match strategy {
+                ChangeDetection::Inode => {
+                    let output = self.remote_exec(sess, &format!("stat -c '%i %Z' -- {}", shell_quote(&file.source)))?;
+                    let mut fields = output.split_whitespace().map(|field| field.parse::<u64>());
+
+                    match (fields.next(), fields.next()) {
+                        (Some(Ok(inode)), Some(Ok(ctime))) => {
+                            entry.inode = inode;
+                            entry.ctime = ctime;
+                        },
+                        _ => return Err(Trap::Metadata(format!("Unexpected output from stat: `{}`", output.trim()))),
+                    }
+                },
+                ChangeDetection::Hash => {
+                    let output = self.remote_exec(sess, &format!("sha256sum -- {}", shell_quote(&file.source)))?;
+                    match output.split_whitespace().next() {
+                        Some(checksum) => entry.checksum = Some(checksum.to_string()),
+                        None => return Err(Trap::Metadata(format!("Unexpected output from sha256sum: `{}`", output.trim()))),
+                    }
+                },
+                _ => (),
+            }
+
+            Ok(entry)
+        }
+
+        /// Copy remote file into the archive at its destination using sess.
+        fn copy_remote_file_with(&self, sess: &Session, file: &RemoteFile) -> Result<(), Trap> {
+            let source = &file.source;
+            let destination = &file.destination;
+
+            let strategy = self.host_config.change_detection.unwrap_or_default();
+            let mut remote_entry = self.remote_entry(sess, file, strategy)?;
+            remote_entry.codec = self.file_codec(source);
+            let dest_as_source = self.into_source(destination)?;
+            let recorded = self.record.snapshot.entries.get(&dest_as_source);
+            
+            if self.incremental {
+                // check the remote state against what is recorded
+                if let Some(recorded) = recorded {
+                    if !remote_entry.has_changed(recorded, strategy) {
+                        println!("{} {}@{}:{:?}", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Skipping")), self.host_config.user, self.host_config.identifier, source);
+                        return Ok(());
+                    }
+                }
+            }
+
+           /*---------------------------------------------------------------------------*
+            * Starting proceess of copying the file from remote into the archive, with  *
+            * the metadata and permissons of the the remote file in its header.         *
+            *---------------------------------------------------------------------------*/
+
+            // Large files with a previous version are patched, only fetching the changed blocks
+            let patched = match (self.host_config.delta_threshold, recorded) {
+                (Some(threshold), Some(recorded)) if remote_entry.size >= threshold => {
+                    match self.receive_file_delta(sess, file, recorded) {
+                        Ok(patched_path) => Some(patched_path),
+                        Err(err) => {
+                            println!("{} Delta transfer failed for {:?}, getting whole file: {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Fallback")), source, err);
+                            None
+                        }
+                    }
+                },
+                _ => None,
+            };
+
+            remote_entry.size = match patched {
+                Some(patched_path) => {
+                    let appended = self.append_local_file(destination, &mut remote_entry, &patched_path);
+                    let _ = fs::remove_file(&patched_path);
+                    appended?
+                },
+                None => self.receive_file(sess, file, &mut remote_entry)?,
+            };
+
+            // Kept until the record is updated
+            if let Ok(mut remote_entries) = self.remote_entries.lock() {
+                remote_entries.insert(source.to_path_buf(), remote_entry);
+            }
+
+            Ok(())
+        }
+
+        /// Receives the whole remote file with scp into the archive (of entry's codec) with the
+        /// metadata of entry, or streaming it into the chunk store. Returns the size of the file.
+        ///
+        /// Workers share the one compression stream, so the file is read into memory or a spool
+        /// file first, and the archive is only locked while it is appended.
+        fn receive_file(&self, sess: &Session, file: &RemoteFile, entry: &mut FileEntry) -> Result<u64, Trap> {
+            let source = &file.source;
+
+            let (channel, scp_stat) = sess.scp_recv(source).map_err(|err| {
+                Trap::Copy(format!("Could not receive file from remote path: {}", err))
             })?;
 
-            let local_modified = local_metadata.modified().map_err(|err| {
-                Trap::Metadata(format!("Could not get mod time of local file: {}", err))
+            let size = scp_stat.size();
+            let stored = match self.chunk_store {
+                Some(_) => self.store_file(&file.destination, entry, size, channel),
+                None => self.store_spooled(&file.destination, entry, size, channel),
+            };
+
+            stored.map_err(|err| {
+                Trap::Copy(format!("Could not stream {:?}: {:?}", source, err))
             })?;
 
-            Ok(local_modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs())
+            println!("{} {}@{}:{:?} ... Done", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), self.host_config.user, self.host_config.identifier, source);
+
+            Ok(size)
         }
 
-        /// Wrapper for SFTP::stat
-        pub fn remote_filestat(&self, remote_file: &Path) -> Result<FileStat, Trap> {
-            let sftp = self.sess.as_ref().ok_or(Trap::FS(String::from("Session unavailable")))?.sftp().map_err(|err| {
+        /// Delta transfer of a file which has a previous version (recorded) in the snapshot chain.
+        ///
+        /// The remote host computes the `cksum` and sha256 of every block of the file
+        /// (`split --filter`, GNU coreutils) over exec channels. Like rsync, the cksum of a window
+        /// is rolled over every offset of the previous version to find the blocks wherever they are,
+        /// confirmed by their sha256, so data shifted by insertions is not fetched again.
+        /// Only the blocks which are not found are read over SFTP.
+        /// Returns the path of the patched file, to be added to the archive.
+        fn receive_file_delta(&self, sess: &Session, file: &RemoteFile, recorded: &FileEntry) -> Result<PathBuf, Trap> {
+            let destination = &file.destination;
+            let block_size = self.host_config.delta_block_size.unwrap_or(1048576).max(1) as usize;
+
+            // Previous version, extracted from its archive (or the chunk store) unless the snapshot is still uncompressed
+            let base_path = self.scratch_path(destination, "base")?;
+            let extracted = !recorded.file_path.exists();
+            if let Some(chunks) = recorded.chunks.as_ref().filter(|_| extracted) {
+                let restored = match &self.chunk_store {
+                    Some(chunk_store) => chunk_store.restore(chunks, &base_path),
+                    None => ChunkStore::open(&chunk_root(self.global_config))?.restore(chunks, &base_path),
+                };
+
+                if let Err(err) = restored {
+                    let _ = fs::remove_file(&base_path);
+                    return Err(Trap::FS(format!("Could not restore previous version from the chunk store: {}", err)));
+                }
+            } else if extracted {
+                let member = recorded.file_path.strip_prefix(&recorded.snapshot_path).map_err(|err| {
+                    Trap::Missing(format!("Previous version is not within its snapshot: {}", err))
+                })?;
+
+                // The last of the member, if it was appended again, found by the index of the archive
+                let archive_path = recorded.archive_path();
+                let copied = fs::File::create(&base_path)
+                    .and_then(|mut base_file| copy_member(&archive_path, recorded.codec, member, &mut base_file));
+
+                let missing = match copied {
+                    Ok(Some(_)) => None,
+                    Ok(None) => Some(Trap::Missing(format!("Previous version not found in {:?}", archive_path))),
+                    Err(err) => Some(Trap::FS(format!("Could not extract previous version from {:?}: {}", archive_path, err))),
+                };
+
+                if let Some(err) = missing {
+                    let _ = fs::remove_file(&base_path);
+                    return Err(err);
+                }
+            }
+
+            let patched_path = self.scratch_path(destination, "patched")?;
+            let result = self.patch_file(sess, file, if extracted { &base_path } else { &recorded.file_path }, &patched_path, block_size);
+
+            if extracted {
+                let _ = fs::remove_file(&base_path);
+            }
+
+            if result.is_err() {
+                let _ = fs::remove_file(&patched_path);
+            }
+
+            result.map(|_| patched_path)
+        }
+
+        /// Writes the remote file to patched_path, taking the blocks found in base_path
+        /// (see `match_blocks`) from it and fetching the rest over SFTP.
+        fn patch_file(&self, sess: &Session, file: &RemoteFile, base_path: &Path, patched_path: &Path, block_size: usize) -> Result<(), Trap> {
+            let source = &file.source;
+
+            let cksums = self.remote_exec(sess, &format!("split -b {} --filter=cksum -- {}", block_size, shell_quote(source)))?;
+            let sha256sums = self.remote_exec(sess, &format!("split -b {} --filter=sha256sum -- {}", block_size, shell_quote(source)))?;
+            let signatures = parse_signatures(&cksums, &sha256sums).ok_or(Trap::Channel(format!("Could not parse the block signatures of {:?}", source)))?;
+
+            let mut base_file = fs::File::open(base_path).map_err(|err| {
+                Trap::FS(format!("Could not open previous version: {}", err))
+            })?;
+
+            let matches = match_blocks(&mut base_file, &signatures).map_err(|err| {
+                Trap::FS(format!("Could not read previous version: {}", err))
+            })?;
+
+            let sftp = sess.sftp().map_err(|err| {
                 Trap::Session(format!("Could not init SFTP session: {}", err))
             })?;
 
-            let stat = sftp.stat(remote_file).map_err(|err| {
-                Trap::Metadata(
-                    format!("Could not get metadata of remote file: {}\nMay be missing or corrupt!", err))
+            let mut remote_file = sftp.open(source).map_err(|err| {
+                Trap::Copy(format!("Could not open remote file: {}", err))
             })?;
 
-            Ok(stat)
+            let mut file_handle = fs::File::create(patched_path).map_err(|err| {
+                Trap::FS(format!("Could not create file: {}\nCheck permissions!", err))
+            })?;
+
+            let mut buffer = vec![0; block_size];
+            let mut fetched = 0;
+            for (i, (signature, matched)) in signatures.iter().zip(&matches).enumerate() {
+                let block = &mut buffer[..signature.size.min(block_size)];
+
+                let bytes_read = match matched {
+                    Some(base_offset) => base_file.seek(SeekFrom::Start(*base_offset))
+                        .and_then(|_| read_block(&mut base_file, block))
+                        .map_err(|err| Trap::FS(format!("Could not read previous version: {}", err)))?,
+                    None => {
+                        fetched += 1;
+                        remote_file.seek(SeekFrom::Start((i * block_size) as u64))
+                            .and_then(|_| read_block(&mut remote_file, block))
+                            .map_err(|err| Trap::Channel(format!("Could not read remote block: {}", err)))?
+                    },
+                };
+
+                // The remote file changed since the signatures were computed
+                if bytes_read != block.len() {
+                    return Err(Trap::Copy(format!("Block {} of {:?} is truncated, got {} of {} bytes", i, source, bytes_read, block.len())));
+                }
+
+                file_handle.write_all(block).map_err(|err| {
+                    Trap::FS(format!("Could not write to file: {}", err))
+                })?;
+            }
+
+            println!("{} {}@{}:{:?} ... Done ({}/{} blocks)", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Patching")), self.host_config.user, self.host_config.identifier, source, fetched, signatures.len());
+
+            Ok(())
+        }
+
+        /// Returns the source root (from the host's sources) which contains remote_path.
+        /// The longest match is used in case the sources are nested.
+        fn source_root(&self, remote_path: &Path) -> Option<&PathBuf> {
+            self.host_config.source.iter()
+                .filter(|source| remote_path.starts_with(source))
+                .max_by_key(|source| source.components().count())
         }
 
-        /// Returns last_modified_time for a remote file from metadata in secs (as u64)
-        fn remote_file_mtime(&self, remote_file: &Path) -> Result<u64, Trap> {
-            Ok(self.remote_filestat(remote_file)?.mtime.unwrap_or(u64::MAX))
+        /// Checks the remote path against the host's exclude/include patterns.
+        /// The patterns are matched relative to the source directory containing it.
+        pub fn is_excluded(&self, remote_path: &Path, is_dir: bool) -> bool {
+            let filter = match &self.filter {
+                Some(filter) => filter,
+                None => return false,
+            };
+
+            let source_root = match self.source_root(remote_path) {
+                Some(source_root) => source_root,
+                None => return false,
+            };
+
+            match remote_path.strip_prefix(source_root) {
+                Ok(relative_path) => filter.is_excluded(relative_path, is_dir),
+                Err(_) => false,
+            }
         }
 
         /// Iterating the keys in entries and checking if they are remotly
         /// accessable still. If not, they are assumed to be deleted from the source,
         /// and therefore marked as deleted.
+        /// Entries that are now excluded are dropped from the record instead,
+        /// as they were not deleted from the source.
         fn update_deleted_entries(&mut self) -> Result<(), Trap> {
             let keys: Vec<_> = self.record.snapshot.entries.keys().cloned().collect();
 
+            // The snapshot being taken, in which the deletions are observed
+            let snapshot = self.snapshot_root_path.as_ref()
+                .and_then(|path| path.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
             for entry in keys {
-                if let Err(_) = self.remote_file_mtime(&entry) {
-                    let pair = PathPair::from(
-                        entry.to_path_buf(),
-                        self.record.snapshot.path(&entry)
-                            .unwrap()
-                            .to_path_buf()
-                    );
-
-                    // println!("Deleting: {:?}", pair);
-                    self.record.snapshot.mark_as_deleted(pair);
+                // Command outputs are gone once their command source is removed from the config
+                if entry.starts_with(COMMAND_ROOT) {
+                    let configured = self.host_config.commands.iter().flatten()
+                        .any(|command_source| Path::new(COMMAND_ROOT).join(&command_source.name) == entry);
+
+                    if !configured {
+                        self.record.snapshot.mark_as_deleted(&entry, &snapshot);
+                    }
+                    continue;
+                }
+
+                if self.is_excluded(&entry, false) {
+                    self.record.snapshot.entries.remove(&entry);
+                    continue;
+                }
+
+                if self.remote_file_lstat(&entry).is_err() {
+                    self.record.snapshot.mark_as_deleted(&entry, &snapshot);
                 }
             }
 
             Ok(())
         }
 
-        pub fn update_entries(&mut self, dir_path: &PathBuf /*, snapshot: &mut Snapshot */) -> Result<(), Trap> {
-            
-            // let snapshot_root_path = Rc::from(self.snapshot_root_path.clone().unwrap());
+        /// Records the files copied into the archive, from the remote state gathered while copying.
+        /// Their file_path is where they are in the snapshot.
+        pub fn update_entries(&mut self) {
             let snapshot_root_path = self.snapshot_root_path.clone().unwrap();
+            let remote_entries = match self.remote_entries.get_mut() {
+                Ok(remote_entries) => std::mem::take(remote_entries),
+                Err(_) => return,
+            };
 
-            if let Ok(entries) = fs::read_dir(dir_path) {
-                for entry in entries {
-                    let entry = match entry {
-                        Ok(entry) => entry,
-                        Err(_) => continue,
-                    };
+            for (source, mut file_entry) in remote_entries {
+                if self.is_excluded(&source, false) {
+                    continue;
+                }
+
+                let _ = self.debug(format!("{} {:?}\n", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Recording")), &source).as_str());
 
-                    let current_path = entry.path();
+                file_entry.file_path = source_subtree(&snapshot_root_path, &source);
+                file_entry.snapshot_path = snapshot_root_path.clone();
 
-                    if current_path.is_dir() {
-                        self.update_entries(&current_path)?;
-                    } else {
+                // If the source is already marked as deleted from a previous backup
+                // (it got readded), will unmark it as deleted.
+                if self.record.snapshot.is_deleted(&source) {
+                    self.record.snapshot.undelete(&source);
+                }
 
-                        // TODO: MULTITHREADING
-                        let source = self.into_source(&current_path)?; 
-                        let mtime = self.local_file_mtime(&current_path)?; 
-                        let size = get_file_sz(&current_path);
-                        let _ = self.debug(format!("{} {:?}... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Recording")), &current_path).as_str());
+                self.record.snapshot.entries.insert(source, file_entry);
+            }
+        }
 
-                        // If the pathpair is already marked as deleted from a previous backup
-                        // (it got readded), will unmark it as deleted. Not checking mtime here
-                        // as it is not relevant.
+        /// Records the links and special files found while walking the remote directories.
+        /// Their file_path is where they would have been in the snapshot.
+        fn update_special_entries(&mut self) {
+            let snapshot_root_path = self.snapshot_root_path.clone().unwrap();
+            let special_entries = match self.special_entries.get_mut() {
+                Ok(special_entries) => std::mem::take(special_entries),
+                Err(_) => return,
+            };
 
-                        let pathpair = PathPair::from(source.clone(), current_path.clone());
-                        if self.record.snapshot.is_deleted(&pathpair) {
-                            self.record.snapshot.undelete(&pathpair);
-                        }
+            for (source, mut entry) in special_entries {
+                entry.file_path = source_subtree(&snapshot_root_path, &source);
+                entry.snapshot_path = snapshot_root_path.clone();
+                entry.codec = self.host_config.compression.unwrap_or_default();
 
-                        self.record.snapshot.entries.insert(source, FileEntry { file_path: current_path, snapshot_path: snapshot_root_path.clone(), mtime, size});
-                        let _ = self.debug("Done\n");
-                    }
+                if self.record.snapshot.is_deleted(&source) {
+                    self.record.snapshot.undelete(&source);
                 }
-            }
 
-            Ok(())
+                self.record.snapshot.entries.insert(source, entry);
+            }
         }
 
-        /// base_path: path to dir the files were copied to.
-        pub fn update_record(&mut self, base_path: &PathBuf) -> Result<(), Trap> {
-            // let mut snapshot = Snapshot::new();
-
-            let _ = self.update_entries(base_path)?;
+        /// Records what was copied into the snapshot, and what was deleted from the sources.
+        pub fn update_record(&mut self) -> Result<(), Trap> {
+            self.update_entries();
+            self.update_special_entries();
             let _ = self.update_deleted_entries()?;
 
             // Count up total size
@@ -181,24 +1252,66 @@ pub mod rsync {
             Ok(())
         }
 
+        /// Replaces the entries of the record with the ones of the last full backup, so that
+        /// files are compared against it rather than the latest snapshot. The deleted entries
+        /// tracked since are kept, and files deleted meanwhile stay deleted from the snapshot
+        /// they were found to be deleted in. Returns false if there is no full backup to compare
+        /// against, every file is copied then.
+        fn load_baseline(&mut self) -> Result<bool, Trap> {
+            let last_full = match &self.record.last_full {
+                Some(last_full) => last_full,
+                None => {
+                    println!("{} No full backup to take a differential one from, taking a full backup", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Warning")));
+                    return Ok(false);
+                }
+            };
+
+            let baseline_path = self.global_config.backups
+                .join(&self.host_config.identifier)
+                .join(".records")
+                .join(format!("{}.json", last_full));
+
+            if !baseline_path.exists() {
+                println!("{} The last full backup {} is gone (pruned?), taking a full backup", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Warning")), last_full);
+                return Ok(false);
+            }
+
+            let baseline = Record::deserialize_json(&baseline_path).map_err(|err| {
+                Trap::Deserialize(format!("Could not deserialize record of the last full backup {:?}: {}", baseline_path, err))
+            })?;
+
+            let deleted_entries = &self.record.snapshot.deleted_entries;
+            self.record.snapshot.entries = baseline.snapshot.entries.into_iter()
+                .filter(|(source, _)| !deleted_entries.contains_key(source))
+                .collect();
+            Ok(true)
+        }
+
         /// Takes in a local_path, and returns it's remote path equvelent according to 'self'
+        /// Every source is placed in its own subtree of the snapshot (see `source_subtree`),
+        /// so the source whose subtree is the longest prefix of current_path is the one it belongs to.
         fn into_source(&self, current_path: &Path) -> Result<PathBuf, Trap> {
-            let mut result = PathBuf::from(self.host_config.source.clone());
-            let current_path_components = current_path.components().collect::<Vec<_>>(); 
+            let snapshot_root_path = self.snapshot_root_path.as_ref()
+                .ok_or(Trap::Missing(String::from("Snapshot root path is not set")))?;
 
-            // Extracting the common prefix between current_path and self.host_config.dest_path
-            // This is so that it can remove the common prefix from the current_path, and replace
-            // it with self.host_config.remote_path instead
-            let common_path_prefix = current_path.components()
-                .zip(self.snapshot_root_path.clone().unwrap().components())
-                .take_while(|(a, b)| a == b)
-                .map(|(a, _)| a)
-                .collect::<Vec<_>>()
-            ;
+            // Output of command sources
+            let command_root = source_subtree(snapshot_root_path, Path::new(COMMAND_ROOT));
+            if let Ok(name) = current_path.strip_prefix(&command_root) {
+                return Ok(Path::new(COMMAND_ROOT).join(name));
+            }
 
-            let ramaining_components = current_path_components.iter().skip(common_path_prefix.len() + 1);
-            for component in ramaining_components {
-                result.push(component);
+            let (source, remaining_path) = self.host_config.source.iter()
+                .filter_map(|source| {
+                    current_path.strip_prefix(source_subtree(snapshot_root_path, source))
+                        .ok()
+                        .map(|remaining_path| (source, remaining_path))
+                })
+                .max_by_key(|(source, _)| source.components().count())
+                .ok_or(Trap::Missing(format!("No source matches local path: {:?}", current_path)))?;
+
+            let mut result = source.clone();
+            if !remaining_path.as_os_str().is_empty() {
+                result.push(remaining_path);
             }
 
             Ok(result)
@@ -209,21 +1322,25 @@ pub mod rsync {
 
         /// Remote sync backup using ssh/sftp
         /// Default port: 22
-        /// Default keypath: "$HOME/.ssh/ed25519
-        /// Compare last-modified timestamp of files with matching namesm,
-        /// ignoring those with matching timestamp. 
+        /// Default keypath: "$HOME/.ssh/ed25519"
+        /// Files are compared against their recorded entry by the `change_detection` of the host
+        /// (mtime by default, mtime and size, inode or content hash, see `FileEntry::has_changed`),
+        /// skipping those which have not changed.
         /// You take one full backup, and the take incremental backups 
-        /// the next days. Put a setting to take a new *full* backup every week or so.
-        /// Backups older than a specific amount (maybe 30 days) will be deleted.
+        /// the next days. A new *full* backup is taken by `full_schedule` or every
+        /// `full_every` incrementals (see rensend), and old snapshots are pruned by the
+        /// retention of the host (see `prune`).
         /// 
         /// ***File structure example***
         ///
         /// 192.168.1.220
         ///     | record.json
         ///     | 2023-01-11_12-34-56.tar.gz
-        ///         | 'remote_path_stem/'
+        ///         | 'etc/'
+        ///         | 'var/lib/app/'
         ///     | 2023-01-12_12-34-56.tar.gz
-        ///         | 'remote_path_stem/'
+        ///         | 'etc/'
+        ///         | 'var/lib/app/'
         ///     | ...tar.gz
         ///
         ///
@@ -235,6 +1352,11 @@ pub mod rsync {
         ///
         fn backup(&mut self) -> Result<(), Trap> {
 
+            // Never falling back to plaintext when encryption is configured
+            if self.global_config.encryption.is_some() && crypto::installed().is_none() {
+                return Err(Trap::Crypto(String::from("Encryption is configured, but the repository key is not unlocked")));
+            }
+
             let _ = self.debug("Connecting to host... ")?;
             self.connect()?;
             let _ = self.debug("Done\n")?;
@@ -243,29 +1365,155 @@ pub mod rsync {
             self.auth()?;
             let _ = self.debug("Done\n")?;
 
-            let datetime = get_datetime();
-            let source = &self.host_config.source;
+            self.filter = Some(PathFilter::from(self.host_config)?);
 
             // $HOME/destination/$identifier
-            self.host_root_path = Some(self.global_config.backups
-                .join(&self.host_config.identifier));
-
-            // $HOME/destination/$identifier/$datetime
-            self.snapshot_root_path = Some(self.host_root_path.clone().unwrap()
-                .join(datetime));
-
-            // $HOME/destination/$identifier/$datetime/dir_name
-            self.complete_destination = if let Some(stem) = &self.host_config.source.file_stem() {
-                Some(self.snapshot_root_path.clone().unwrap().join(stem))
-            } else {
-                Some(self.snapshot_root_path.clone().unwrap().join(format!("{}", self.host_config.identifier)))
+            let host_root_path = self.global_config.backups.join(&self.host_config.identifier);
+            self.host_root_path = Some(host_root_path.clone());
+            fs::create_dir_all(&host_root_path).map_err(|err| {
+                Trap::FS(format!("Could not create directory: {}", err))
+            })?;
+
+            // Held until the backup is recorded and pruned. The record is read again once it is taken,
+            // as a backup or synthesis which ran meanwhile has changed it.
+            let _host_lock = lock_host(&host_root_path)?;
+
+            let record_path = host_root_path.join(".records").join("record.json");
+            if record_path.exists() {
+                self.record = Record::deserialize_json(&record_path).map_err(|err| {
+                    Trap::Deserialize(format!("Could not deserialize record {:?}: {}", record_path, err))
+                })?;
+            }
+
+            let datetime = get_datetime();
+
+            // $HOME/destination/$identifier/$datetime, the archives of which files are streamed into as they are copied
+            self.snapshot_root_path = Some(host_root_path.join(&datetime));
+
+            // A differential backup is an incremental one on top of the last full backup
+            if self.differential {
+                self.incremental = self.load_baseline()?;
+            }
+
+            let codec = self.host_config.compression.unwrap_or_default();
+            self.record.codec = codec;
+
+            // A first backup copies every file, whichever was asked for
+            let kind = match self.incremental && !self.record.snapshot.entries.is_empty() {
+                true if self.differential => BackupKind::Differential,
+                true => BackupKind::Incremental,
+                false => BackupKind::Full,
             };
 
+            self.record.kind = Some(kind);
+            match kind {
+                BackupKind::Incremental => self.record.incrementals += 1,
+                BackupKind::Full => {
+                    self.record.incrementals = 0;
+                    self.record.last_full = Some(datetime.clone());
+                },
+                _ => (),
+            }
+
+            // Deduplicated files are stored once, in the chunk store shared by all hosts
+            match self.host_config.storage.unwrap_or_default() {
+                StorageMode::Dedup => self.chunk_store = Some(ChunkStore::open(&chunk_root(self.global_config))?),
+                StorageMode::Archive => self.with_archive(codec, |_| Ok(()))?,
+            }
+
+            // Files sharing an inode are recorded as hard links to the first of them
+            let host_config = self.host_config;
+            for source in host_config.source.iter() {
+                let hardlinks = self.find_hardlinks(&*self.session()?, source);
+                match hardlinks {
+                    Ok(hardlinks) => self.hardlinks.extend(hardlinks),
+                    Err(err) => {
+                        println!("{} Could not look for hard links in {:?}: {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Warning")), source, err);
+                    }
+                }
+            }
+
+            // Owners are recorded by name as well as by id
+            let owners = self.find_owners(&*self.session()?);
+            match owners {
+                Ok((users, groups)) => {
+                    self.users = users;
+                    self.groups = groups;
+                },
+                Err(err) => {
+                    println!("{} Could not look up user and group names: {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Warning")), err);
+                }
+            }
+
+            // Pre-backup hooks (dumps, snapshots, freezes), a failing one aborts the backup
+            self.run_hooks("pre_backup", self.host_config.pre_backup.as_deref().unwrap_or_default())?;
+
             // Start backup
-            self.copy_remote_directory(&source, &self.complete_destination.clone().unwrap())?;
+            let mut copied = Ok(());
+            for source in self.host_config.source.iter() {
+
+                // $HOME/destination/$identifier/$datetime/source/path
+                let destination = source_subtree(&self.snapshot_root_path.clone().unwrap(), source);
+                copied = self.copy_remote_directory(source, &destination);
+                if copied.is_err() {
+                    break;
+                }
+            }
+
+            // Command sources (database dumps and such)
+            if copied.is_ok() {
+                for command_source in self.host_config.commands.iter().flatten() {
+                    if let Err(err) = self.copy_command_output(command_source) {
+                        println!("{} {}: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), command_source.name, err);
+                        log_trap(self.global_config, &err);
+                    }
+                }
+            }
+
+            // Post-backup hooks clean up after the pre-backup hooks, so they run even if copying failed
+            if let Err(err) = self.run_hooks("post_backup", self.host_config.post_backup.as_deref().unwrap_or_default()) {
+                println!("{} {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Warning")), err);
+                log_trap(self.global_config, &err);
+            }
+
+            // Working files (delta bases, command outputs) are all in the snapshot directory
+            let snapshot_root_path_binding = self.snapshot_root_path.clone().unwrap();
+            let _ = fs::remove_dir_all(&snapshot_root_path_binding);
+
+            copied?;
 
             let _ = self.debug("Updating records\n")?;
-            self.update_record(&mut self.snapshot_root_path.clone().unwrap())?;
+            self.update_record()?;
+            let _ = self.debug("Done\n")?;
+
+            // Entries observed in this snapshot, giving the remote ownership of the files.
+            // Hard links are only added when the file they link to is in this archive as well.
+            let special_entries: Vec<(&PathBuf, &FileEntry)> = self.record.snapshot.entries.iter()
+                .filter(|(_, entry)| !entry.is_file() && entry.snapshot_path == snapshot_root_path_binding)
+                .filter(|(_, entry)| {
+                    entry.kind != EntryKind::HardLink || entry.link_target.as_ref()
+                        .and_then(|target| self.record.snapshot.entries.get(target))
+                        .map(|target| target.is_file() && target.snapshot_path == snapshot_root_path_binding && target.codec == codec)
+                        .unwrap_or(false)
+                })
+                .collect();
+
+            // The archives are moved into place before the records refer to them
+            let _ = self.debug("Compressing... ")?;
+            let mut archives = std::mem::take(self.archives.get_mut()
+                .map_err(|err| Trap::FS(format!("Archives are poisoned: {}", err)))?);
+
+            if let Some(archive) = archives.get_mut(&codec) {
+                archive.append_special_entries(&special_entries).map_err(|err| {
+                    Trap::FS(format!("Could not add links and special files to archive: {}", err))
+                })?;
+            }
+
+            for (codec, archive) in archives {
+                archive.finish().map_err(|err| {
+                    Trap::FS(format!("Could not finish archive {:?}: {}", archive_path(&snapshot_root_path_binding, codec), err))
+                })?;
+            }
             let _ = self.debug("Done\n")?;
 
             // $HOME/destination/$identifier/.records
@@ -282,23 +1530,44 @@ pub mod rsync {
             let _ = self.record.serialize_json(&record_dir_path.join("record.json"));
             let _ = self.debug("Done\n");
 
-            let snapshot_root_path_binding = self.snapshot_root_path.clone().unwrap();
             let snapshot_root_file_stem = match snapshot_root_path_binding.file_name() {
                 Some(stem) => stem,
                 _ => &OsStr::new("broken")
             };
 
-            let _ = self.record.serialize_json(&record_dir_path.join(
+            let written = self.record.serialize_json(&record_dir_path.join(
                 format!("{}.json", snapshot_root_file_stem.to_str().unwrap_or("broken"))
             ));
 
-            // Compressing and archive
-            let archive_compress_dest: &str = snapshot_root_path_binding.to_str().unwrap();
+            // Every dated record references the chunks of its entries, deleted ones included, until it is pruned
+            let chunks: Vec<&String> = self.record.snapshot.retained_entries()
+                .filter_map(|entry| entry.chunks.as_ref())
+                .flatten()
+                .collect();
 
-            let _ = make_tar_gz(
-                self.snapshot_root_path.clone().unwrap(),
-                format!("{}.tar.gz", archive_compress_dest)
-            );
+            if written.is_ok() && !chunks.is_empty() {
+                let chunk_store = match self.chunk_store.take() {
+                    Some(chunk_store) => chunk_store,
+                    None => ChunkStore::open(&chunk_root(self.global_config))?,
+                };
+                chunk_store.add_refs(chunks.into_iter())?;
+            }
+
+            // Applying the retention of the host, the backup itself succeeded either way
+            if self.host_config.retention.is_some() {
+                match prune_locked(self.global_config, self.host_config) {
+                    Ok(report) => {
+                        if !report.pruned.is_empty() {
+                            log_message(self.global_config, &format!("Pruned {} snapshots of {}: {}", report.pruned.len(), self.host_config.identifier, report.pruned.join(", ")));
+                        }
+                        println!("{} {} snapshots ({} archives, {} chunks), keeping {}", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Pruned")), report.pruned.len(), report.archives, report.chunks, report.kept.len());
+                    },
+                    Err(err) => {
+                        println!("{} Could not prune snapshots: {:?}", <Style as Clone>::clone(&self.style).bold().yellow().apply_to(String::from("Warning")), err);
+                        log_trap(self.global_config, &err);
+                    }
+                }
+            }
 
             let _ = self.debug("Status: OK\n")?;
             
@@ -306,175 +1575,124 @@ pub mod rsync {
         }
 
         fn auth(&mut self) -> Result<(), Trap> {
+            let session = self.session()
+                .map_err(|_| Trap::Auth(String::from("Sessions is None")))?;
 
-            // key path
-            let default_key_path = "$HOME/.ssh/ed25519";
-            let key_path = self.host_config.key.as_ref()
-                .map(|s| s.to_str().unwrap_or(default_key_path))
-                .unwrap_or(default_key_path);
-
-            let private_key_path = Path::new(&key_path);
-
-            // Authenticate session (private key --> public key)
-            match self.sess.as_ref() {
-                Some(session) => {
-                    if let Err(err) = session.userauth_pubkey_file(&self.host_config.user, None, private_key_path, None) {
-                        return Err(Trap::Auth(
-                                format!("Could not Authenticate session: {}\nMake sur ethe ssh-key is at hosts specified key-path", err)
-                                )
-                        );
-                    }
-                },
-                None => {
-                    return Err(Trap::Auth(String::from("Sessions is None")));
-                }
-            }
-
-            Ok(())
+            self.auth_session(&session)
         }
 
         fn connect(&mut self) -> Result<(), Trap> {
-            let identifier = &self.host_config.identifier;
-            let port = self.host_config.port.unwrap_or(22);
-
-            // Connect to SSH server
-            let tcp = TcpStream::connect(format!("{}:{}", identifier, port)).map_err(|err| {
-                Trap::Connect(format!("Could not connect to host: {}\nHost unreachable!", err))
-
-            })?;
-
-            // Create SSH session
-            let mut sess = Session::new().map_err(|err| {
-                Trap::Session(format!("Could not create SSH session: {}", err))
-
-            })?;
-
-            // Perform SSH handshake
-            sess.set_tcp_stream(tcp);
-            sess.handshake().map_err(|err| {
-                Trap::Handshake(format!("Could not perform SSH handshake: {}", err))
-            })?;
-
-            self.sess = Some(sess);
+            let sess = self.with_retries(|| self.open_session())?;
+            self.sess = Some(Mutex::new(sess));
             Ok(())
         }
         
         /// Copy remote directory to destination.
-        /// Will recurse and call copy_remote_file(...) until all contents are copied.
+        /// Walks the directory and then copies all of its files with copy_remote_files(...)
         fn copy_remote_directory(&self, source: &Path, destination: &Path) -> Result<(), Trap> {
-            // Create destination directory if it doesn't exist
-            if !destination.exists() {
-                fs::create_dir_all(destination).map_err(|err| {
-                    Trap::FS(format!("Could not create directory: {}", err))
+            let mut files: Vec<RemoteFile> = Vec::new();
 
+            {
+                let session = self.session()?;
+                let sftp = session.sftp().map_err(|err| {
+                    Trap::Copy(format!("Could not init SFTP: {}", err))
                 })?;
-            }
-            
-            let dir_entries = self.sess.as_ref().unwrap().sftp().map_err(|err| {
-                Trap::Copy(format!("Could not init SFTP: {}", err))
-
-            })?
-            .readdir(source).map_err(|err| {
-                Trap::Copy(format!("Could not read remote directory: {}", err))
 
-            })?;
-
-            for (entry, stat) in dir_entries {
-                let entryname = match entry.file_name() {
-                    Some(entryname) => {
-                        entryname 
-                    },
-                    None => {
-                        continue;
-                    },
-                };
-
-                // format paths
-                let new_source = source.join(entryname);
-                let new_destination = destination.join(entryname);
-
-                if stat.is_file() {
-                    match self.copy_remote_file(&new_source, &new_destination) {
-                        Ok(_) => (),
-                        Err(err) => { 
-                            println!("{} Could not receive file, please check permissions: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
-                        }
-                    }
-                }
-                else if stat.is_dir() {
-                    let destination_subdir = destination.join(&entryname);
-                    fs::create_dir_all(&destination_subdir).map_err(|err| {
-                        Trap::FS(format!("Could not create directory: {}\nCheck permissions!", err))
-                    })?;
+                let stat = sftp.stat(source).map_err(|err| {
+                    Trap::Copy(format!("Could not get metadata of remote directory: {}", err))
+                })?;
 
-                    match self.copy_remote_directory(&new_source, &new_destination) {
-                        Ok(_) => (),
-                        Err(err) => { 
-                            println!("{} Directory out of reach, please check permissions: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
-                        }
-                    }
-                }
+                self.append_dir(destination, &stat)?;
+                self.collect_remote_files(&session, &sftp, source, destination, &mut files)?;
             }
-           
-            Ok(())
+
+            self.copy_remote_files(files)
         }
 
         /// Copy remote file (source) to destination.
         fn copy_remote_file(&self, source: &Path, destination: &Path) -> Result<(), Trap> {
-            // TODO: MULTITHREADING
-            
-            if self.incremental {
-                // check mtime data at local and source
-                let remote_mtime: &u64 = &self.remote_file_mtime(source)?; 
-
-                let dest_as_source = self.into_source(destination)?;
-                if remote_mtime <= self.record.snapshot.mtime(&dest_as_source).unwrap_or(&0) {
-                    println!("{} {}@{}:{:?}", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Skipping")), self.host_config.user, self.host_config.identifier, source);
-                    return Ok(());
-                }
-            }
+            let file = RemoteFile {
+                source: source.to_path_buf(),
+                destination: destination.to_path_buf(),
+                stat: self.remote_filestat(source)?,
+            };
 
-           /*---------------------------------------------------------------------------*
-            * Starting proceess of copying the file from remote to locally, also ensuring*
-            * metadata and permissons of the the file.                                  *
-            * Need to be run in sudo if it is going to write in /
-            *---------------------------------------------------------------------------*/
+            let session = self.session()?;
+            self.copy_remote_file_with(&session, &file)
+        }
+    }
 
-            let (mut channel, _) = self.sess.as_ref().unwrap().scp_recv(source).map_err(|err| {
-                Trap::Copy(format!("Could not receive file from remote path: {}", err))
-            })?;
+    /// Reads stdout and stderr of a non-blocking channel until eof, or fails when past deadline
+    fn read_channel_until(channel: &mut Channel, deadline: Instant) -> Result<(Vec<u8>, Vec<u8>), Trap> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut buffer = vec![0; 8192];
 
+        while !channel.eof() {
+            if Instant::now() > deadline {
+                return Err(Trap::Channel(String::from("Timed out")));
+            }
 
-            let mut file = fs::File::create(destination).map_err(|err| {
-                Trap::FS(format!("Could not create file: {}\nCheck permissions!", err))
-            })?;
+            let stdout_read = read_available(channel, &mut buffer, &mut stdout)?;
+            let stderr_read = read_available(&mut channel.stderr(), &mut buffer, &mut stderr)?;
 
-            print!("{} {}@{}:{:?} ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), self.host_config.user, self.host_config.identifier, source);
-            let mut buffer = [0; 4096];
-            loop {
-                match channel.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        file.write_all(&buffer[..n]).map_err(|err| {
-                            Trap::FS(format!("Could not write to file: {}", err))
-                        })?;
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                    Err(err) => {
-                        return Err(Trap::Channel(format!("Could not read from channel: {}", err)));
-                    }
-                }
+            if !stdout_read && !stderr_read {
+                thread::sleep(Duration::from_millis(10));
             }
-            println!("Done");
+        }
 
-            // Sets metadata for the newly created file to the same as the remote file.
-            // print!("Copying metadata... ");            
-            let stat = self.remote_filestat(source)?;
-            let _ = set_metadata(&mut file, stat);
-            // println!("Done");
+        // What arrived along with eof
+        while read_available(channel, &mut buffer, &mut stdout)? {}
+        while read_available(&mut channel.stderr(), &mut buffer, &mut stderr)? {}
 
-            Ok(())
+        Ok((stdout, stderr))
+    }
+
+    /// Appends what is available from reader to output, returns false if nothing was
+    fn read_available<R: Read>(reader: &mut R, buffer: &mut [u8], output: &mut Vec<u8>) -> Result<bool, Trap> {
+        match reader.read(buffer) {
+            Ok(n) => {
+                output.extend_from_slice(&buffer[..n]);
+                Ok(n > 0)
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(Trap::Channel(format!("Could not read output: {}", err))),
+        }
+    }
+
+    /// Authenticates sess as user with the auth method,
+    /// shared by hosts and the jump hosts they are reached through.
+    fn authenticate(sess: &Session, user: &str, auth: Option<AuthMethod>, key: Option<&PathBuf>, secret: Option<&Secret>) -> Result<(), Trap> {
+        let secret = match secret {
+            Some(secret) => Some(secret.read()?),
+            None => None,
+        };
+
+        match auth.unwrap_or_default() {
+            AuthMethod::Key => {
+                // key path, `~` and `$HOME` expanded
+                let default_key_path = PathBuf::from("$HOME/.ssh/ed25519");
+                let private_key_path = expand_home(key.unwrap_or(&default_key_path));
+
+                // Authenticate session (private key --> public key)
+                sess.userauth_pubkey_file(user, None, &private_key_path, secret.as_deref()).map_err(|err| {
+                    Trap::Auth(format!("Could not Authenticate session: {}\nMake sure the ssh-key is at {:?} and the passphrase is correct", err, private_key_path))
+                })?;
+            },
+            AuthMethod::Agent => {
+                sess.userauth_agent(user).map_err(|err| {
+                    Trap::Auth(format!("Could not Authenticate session with ssh-agent: {}\nMake sure SSH_AUTH_SOCK is set and the agent holds the key", err))
+                })?;
+            },
+            AuthMethod::Password => {
+                let password = secret.ok_or(Trap::Config(String::from("Password auth needs a secret to read the password from")))?;
+                sess.userauth_password(user, &password).map_err(|err| {
+                    Trap::Auth(format!("Could not Authenticate session with password: {}", err))
+                })?;
+            },
         }
+
+        Ok(())
     }
 
     pub struct Samba {}
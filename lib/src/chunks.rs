@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use fastcdc::v2020::StreamCDC;
+
+use crate::logging::Trap;
+use crate::config::GlobalConfig;
+use crate::crypto::{self, Encryptor, decrypting_reader};
+
+/// Bounds of the content-defined chunks (bytes). Cut points depend on the content only,
+/// so data shifted by insertions still ends up in the same chunks.
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+const CHUNK_ZSTD_LEVEL: i32 = 3;
+
+/// Unreferenced chunks younger than this are kept by `gc`, as a backup which is still
+/// running may be about to reference them.
+const GC_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Serializes the read-modify-write of index.json within the process, as every backup, prune
+/// and synthesis opens a store of its own. Other processes are kept out by a lock on index.lock.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// Makes the temp files of index writes unique within the process
+static INDEX_WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// The chunk store, in the root of the backups, shared by all hosts
+pub fn chunk_root(global_config: &GlobalConfig) -> PathBuf {
+    global_config.backups.join(".chunks")
+}
+
+/// Name of a chunk with data: its sha256, or its keyed hash once the repository key is
+/// installed, so that the names of the chunks do not reveal which contents are stored.
+fn chunk_id(data: &[u8]) -> String {
+    match crypto::installed() {
+        Some(keys) => keys.chunk_id(data),
+        None => format!("{:x}", Sha256::digest(data)),
+    }
+}
+
+/// Reference counts of the chunks (hash: refs). A chunk is referenced once by every
+/// snapshot record that has an entry made up of it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChunkIndex {
+    refs: BTreeMap<String, u64>,
+}
+
+/// Content-addressed storage of file contents. Files are split into chunks with
+/// content-defined chunking (FastCDC), and every chunk is stored once by its hash (see `chunk_id`),
+/// zstd compressed and encrypted with the installed keys.
+///
+/// ***File structure***
+///
+/// .chunks
+///     | index.json
+///     | index.lock
+///     | 3f
+///         | 3f9a...e1
+///     | ...
+pub struct ChunkStore {
+    root: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn open(root: &Path) -> Result<Self, Trap> {
+        fs::create_dir_all(root).map_err(|err| {
+            Trap::FS(format!("Could not create chunk store {:?}: {}", root, err))
+        })?;
+
+        Ok(ChunkStore { root: root.to_path_buf() })
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(hash)
+    }
+
+    /// Splits data into chunks and stores the ones not in the store yet.
+    /// Returns the hashes of the chunks in order, and the size of data.
+    pub fn store<R: Read>(&self, data: R) -> Result<(Vec<String>, u64), Trap> {
+        let mut hashes = Vec::new();
+        let mut size = 0;
+
+        for chunk in StreamCDC::new(data, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let chunk = chunk.map_err(|err| Trap::Copy(format!("Could not read data to chunk: {}", err)))?;
+            let hash = chunk_id(&chunk.data);
+
+            self.write_chunk(&hash, &chunk.data)?;
+            size += chunk.length as u64;
+            hashes.push(hash);
+        }
+
+        Ok((hashes, size))
+    }
+
+    /// Writes the chunk unless it is stored already. An existing chunk is touched,
+    /// so it is not garbage collected while the snapshot referencing it is being taken.
+    fn write_chunk(&self, hash: &str, data: &[u8]) -> Result<(), Trap> {
+        let path = self.chunk_path(hash);
+        if path.exists() {
+            let _ = File::options().write(true).open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            return Ok(());
+        }
+
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent).map_err(|err| {
+            Trap::FS(format!("Could not create directory {:?}: {}", parent, err))
+        })?;
+
+        let compressed = zstd::encode_all(data, CHUNK_ZSTD_LEVEL).map_err(|err| {
+            Trap::FS(format!("Could not compress chunk {}: {}", hash, err))
+        })?;
+
+        // Unique per process and thread, as workers may store the same chunk at once
+        let temp_path = PathBuf::from(format!("{}.{}.{:?}.tmp", path.display(), process::id(), std::thread::current().id()));
+        let written = File::create(&temp_path)
+            .and_then(|file| match crypto::installed() {
+                Some(keys) => {
+                    let mut encryptor = Encryptor::new(file, &keys)?;
+                    encryptor.write_all(&compressed)?;
+                    encryptor.finish()?.sync_all()
+                },
+                None => {
+                    let mut file = file;
+                    file.write_all(&compressed)?;
+                    file.sync_all()
+                },
+            })
+            .and_then(|_| fs::rename(&temp_path, &path));
+
+        written.map_err(|err| {
+            let _ = fs::remove_file(&temp_path);
+            Trap::FS(format!("Could not write chunk {}: {}", hash, err))
+        })
+    }
+
+    /// Reads a chunk, verifying it against its hash. Chunks stored before they were named by
+    /// a keyed hash are named by their sha256.
+    pub fn read_chunk(&self, hash: &str) -> io::Result<Vec<u8>> {
+        let file = File::open(self.chunk_path(hash))?;
+        let data = zstd::decode_all(decrypting_reader(file)?)?;
+
+        if chunk_id(&data) != hash && format!("{:x}", Sha256::digest(&data)) != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Chunk {} is corrupt", hash)));
+        }
+
+        Ok(data)
+    }
+
+    /// Reader of the contents made up of chunks
+    pub fn reader<'a>(&'a self, chunks: &'a [String]) -> ChunkReader<'a> {
+        ChunkReader { store: self, chunks, next: 0, data: Vec::new(), position: 0 }
+    }
+
+    /// Writes the contents made up of chunks to destination
+    pub fn restore(&self, chunks: &[String], destination: &Path) -> io::Result<u64> {
+        let mut file = File::create(destination)?;
+        io::copy(&mut self.reader(chunks), &mut file)
+    }
+
+    /// Adds a reference to every chunk of a snapshot record (each distinct chunk once)
+    pub fn add_refs<'a>(&self, chunks: impl Iterator<Item = &'a String>) -> Result<(), Trap> {
+        let chunks: BTreeSet<&String> = chunks.collect();
+        self.update_index(|index| {
+            for hash in chunks {
+                *index.refs.entry(hash.clone()).or_default() += 1;
+            }
+        })
+    }
+
+    /// Removes the references of a snapshot record which is deleted
+    pub fn remove_refs<'a>(&self, chunks: impl Iterator<Item = &'a String>) -> Result<(), Trap> {
+        let chunks: BTreeSet<&String> = chunks.collect();
+        self.update_index(|index| {
+            for hash in chunks {
+                if let Some(refs) = index.refs.get_mut(hash) {
+                    *refs = refs.saturating_sub(1);
+                }
+            }
+        })
+    }
+
+    /// Deletes the chunks no snapshot references anymore (see GC_GRACE), including the ones
+    /// left behind by failed backups. Returns the number of chunks deleted and the bytes freed.
+    pub fn gc(&self) -> Result<(usize, u64), Trap> {
+        let mut deleted = (0, 0);
+        let now = SystemTime::now();
+
+        self.update_index(|index| {
+            index.refs.retain(|_, refs| *refs > 0);
+
+            let chunk_files = fs::read_dir(&self.root).into_iter().flatten().flatten()
+                .filter(|dir| dir.path().is_dir())
+                .flat_map(|dir| fs::read_dir(dir.path()).into_iter().flatten().flatten());
+
+            for chunk_file in chunk_files {
+                let hash = chunk_file.file_name().to_string_lossy().into_owned();
+                if index.refs.contains_key(&hash) {
+                    continue;
+                }
+
+                let metadata = match chunk_file.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+
+                let young = metadata.modified()
+                    .map(|modified| now.duration_since(modified).unwrap_or_default() < GC_GRACE)
+                    .unwrap_or(true);
+
+                if !young && fs::remove_file(chunk_file.path()).is_ok() {
+                    deleted.0 += 1;
+                    deleted.1 += metadata.len();
+                }
+            }
+        })?;
+
+        Ok(deleted)
+    }
+
+    /// Loads the index, applies update and writes it back (moved into place), while holding
+    /// the index lock of the process and an exclusive lock on index.lock against other processes.
+    /// The index is encrypted like the records, as it lists the hashes of the contents.
+    fn update_index(&self, update: impl FnOnce(&mut ChunkIndex)) -> Result<(), Trap> {
+        let _guard = INDEX_LOCK.lock().map_err(|err| Trap::FS(format!("Chunk index is poisoned: {}", err)))?;
+
+        // Released when the file is closed
+        let lock_path = self.root.join("index.lock");
+        let lock_file = File::options().create(true).truncate(false).write(true).open(&lock_path)
+            .and_then(|file| file.lock().map(|_| file))
+            .map_err(|err| Trap::FS(format!("Could not lock chunk index {:?}: {}", lock_path, err)))?;
+
+        let path = self.root.join("index.json");
+
+        let mut index = match File::open(&path) {
+            Ok(file) => {
+                let mut contents = String::new();
+                decrypting_reader(file)
+                    .and_then(|mut reader| reader.read_to_string(&mut contents))
+                    .map_err(|err| Trap::FS(format!("Could not read chunk index {:?}: {}", path, err)))?;
+
+                serde_json::from_str(&contents).map_err(|err| {
+                    Trap::Deserialize(format!("Could not deserialize chunk index {:?}: {}", path, err))
+                })?
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => ChunkIndex::default(),
+            Err(err) => return Err(Trap::FS(format!("Could not open chunk index {:?}: {}", path, err))),
+        };
+
+        update(&mut index);
+
+        let json = serde_json::to_string(&index).map_err(|err| {
+            Trap::Serialize(format!("Could not serialize chunk index: {}", err))
+        })?;
+
+        let temp_path = self.root.join(format!("index.json.{}.{}.tmp", process::id(), INDEX_WRITES.fetch_add(1, Ordering::Relaxed)));
+        let written = File::create(&temp_path)
+            .and_then(|file| match crypto::installed() {
+                Some(keys) => {
+                    let mut encryptor = Encryptor::new(file, &keys)?;
+                    encryptor.write_all(json.as_bytes())?;
+                    encryptor.finish().map(|_| ())
+                },
+                None => {
+                    let mut file = file;
+                    file.write_all(json.as_bytes())
+                },
+            })
+            .and_then(|_| fs::rename(&temp_path, &path));
+
+        let written = written.map_err(|err| {
+            let _ = fs::remove_file(&temp_path);
+            Trap::FS(format!("Could not write chunk index {:?}: {}", path, err))
+        });
+
+        drop(lock_file);
+        written
+    }
+}
+
+/// Reads the contents made up of a list of chunks, one chunk at a time
+pub struct ChunkReader<'a> {
+    store: &'a ChunkStore,
+    chunks: &'a [String],
+    next: usize,
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.data.len() {
+            match self.chunks.get(self.next) {
+                Some(hash) => {
+                    self.data = self.store.read_chunk(hash)?;
+                    self.position = 0;
+                    self.next += 1;
+                },
+                None => return Ok(0),
+            }
+        }
+
+        let n = (self.data.len() - self.position).min(buf.len());
+        buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_chunk_store() {
+    let dir = std::env::temp_dir().join(format!("rensen-test-chunks-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let store = ChunkStore::open(&dir).unwrap();
+
+    // Pseudo random, so the chunks are cut by content rather than at the maximum size
+    let mut state: u32 = 1;
+    let data: Vec<u8> = (0..3 * MAX_CHUNK_SIZE).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect();
+
+    let (chunks, size) = store.store(&data[..]).unwrap();
+    assert_eq!(size, data.len() as u64);
+    assert!(chunks.len() > 1);
+
+    let mut restored = Vec::new();
+    store.reader(&chunks).read_to_end(&mut restored).unwrap();
+    assert_eq!(restored, data);
+
+    // Data inserted up front only changes the first chunk(s)
+    let mut shifted = b"inserted".to_vec();
+    shifted.extend_from_slice(&data);
+    let (shifted_chunks, _) = store.store(&shifted[..]).unwrap();
+    assert!(shifted_chunks.iter().skip(1).filter(|hash| chunks.contains(hash)).count() >= chunks.len() - 2);
+
+    // Referenced chunks and young ones are kept
+    store.add_refs(chunks.iter()).unwrap();
+    assert_eq!(store.gc().unwrap().0, 0);
+    store.remove_refs(chunks.iter()).unwrap();
+    assert_eq!(store.gc().unwrap().0, 0);
+    assert!(store.read_chunk(&chunks[0]).is_ok());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_concurrent_refs() {
+    let dir = std::env::temp_dir().join(format!("rensen-test-chunk-refs-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    // Stores of their own, like backups of different hosts running at once
+    let hash = String::from("00ab");
+    let threads: Vec<_> = (0..4).map(|_| {
+        let (dir, hash) = (dir.clone(), hash.clone());
+        std::thread::spawn(move || {
+            let store = ChunkStore::open(&dir).unwrap();
+            for _ in 0..25 {
+                store.add_refs([&hash].into_iter()).unwrap();
+            }
+        })
+    }).collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut refs = 0;
+    ChunkStore::open(&dir).unwrap().update_index(|index| refs = index.refs[&hash]).unwrap();
+    assert_eq!(refs, 100);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,15 +1,20 @@
 use std::path::{Path, PathBuf};
 use std::fs;
+use fxhash::FxHashSet;
 
 use crate::logging::*;
 use crate::snapshot::*; use crate::utils::*; use crate::traits::JsonFile;
 use crate::utils::make_tar_gz;
+use crate::archive::{Codec, archive_path};
+use crate::chunks::ChunkStore;
 
 use crate::record::Record;
 
 pub struct Compiler {
     pub source_snapshot_path: PathBuf,
     pub source_snapshot: Snapshot,
+    pub codec: Codec, // codec of the compiled archive, the one of the record
+    pub chunk_root: PathBuf, // chunk store of the backups the record is in
 }
 
 impl Compiler {
@@ -22,9 +27,14 @@ impl Compiler {
             }
         };
 
+        // $backups/$identifier/.records/$datetime.json -> $backups/.chunks
+        let chunk_root = record_path.ancestors().nth(3)
+            .map(|backups| backups.join(".chunks"))
+            .unwrap_or_default();
+
         let mut record_path = record_path.clone();
         strip_extension(&mut record_path);
-        Ok(Compiler { source_snapshot_path: record_path.to_path_buf(), source_snapshot: record.snapshot })
+        Ok(Compiler { source_snapshot_path: record_path.to_path_buf(), source_snapshot: record.snapshot, codec: record.codec, chunk_root })
     } 
 
     /// Compiles from self.snapshot to destination
@@ -38,28 +48,58 @@ impl Compiler {
         let full_destination = destination.join(self.source_snapshot_path.file_name().unwrap());
         let _ = fs::create_dir_all(&full_destination);
 
+        // Every entry is passed on to the archive: files keep their remote
+        // ownership, links and special files are added as headers.
+        let mut entries = Vec::new();
+
+        // A snapshot may be split over archives of different codecs, each is demaked once
+        let mut demaked: FxHashSet<PathBuf> = FxHashSet::default();
+        let mut chunk_store: Option<ChunkStore> = None;
+
         for entry in &self.source_snapshot.entries {
+            entries.push(entry);
+            if !entry.1.is_file() {
+                continue;
+            }
+
+            // Deduplicated files are put together from the chunk store
+            if let Some(chunks) = &entry.1.chunks {
+                if chunk_store.is_none() {
+                    chunk_store = Some(ChunkStore::open(&self.chunk_root)?);
+                }
+
+                let file_destination = source_subtree(&full_destination, entry.0);
+                if let Some(parent) = file_destination.parent() {
+                    let _ = fs::create_dir_all(parent);
+                }
+
+                chunk_store.as_ref().unwrap().restore(chunks, &file_destination).map_err(|err| {
+                    Trap::FS(format!("Could not restore {:?} from the chunk store: {}", entry.0, err))
+                })?;
+                continue;
+            }
+
             let file_path = &entry.1.file_path;
             let snapshot_path = &entry.1.snapshot_path;
 
-            // if a demaked version of the snapshot does not already exist
-            if !snapshot_path.exists() {
-                let _ = demake_tar_gz(
-                    format!("{}.tar.gz", entry.1.snapshot_path.as_path().to_str().unwrap()),
-                    snapshot_path
-                );  
+            // if the archive of the entry is not already demaked
+            let archive_path = entry.1.archive_path();
+            if !demaked.contains(&archive_path) {
+                let _ = demake_tar_gz(&archive_path, snapshot_path, entry.1.codec);
+                demaked.insert(archive_path);
             }
 
             // The complete file destination 
             // (aka where it will collected with all other files in
-            // the recored)
-            let file_destination = replace_common_prefix(&file_path, &snapshot_path, &full_destination.to_path_buf());
+            // the recored). Placed by its source path, so every source
+            // root of the host gets its own subtree.
+            let file_destination = source_subtree(&full_destination, entry.0);
             let _ = force_copy(&file_path, &file_destination);
 
         }
 
         // Because `full_snapshot_path` is the `source` in this matter.
-        make_tar_gz(&full_destination, format!("{}.tar.gz", full_destination.to_str().unwrap()))
+        make_tar_gz(&full_destination, archive_path(&full_destination, self.codec), &entries, self.codec)
             .map_err(|err| Trap::FS(format!("Could not archive and compress snapshot: {}", err)))?;
 
         println!("Done");
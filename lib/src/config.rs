@@ -1,4 +1,4 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 use serde_json;
 use serde_yaml;
 use std::fs::File;
@@ -7,6 +7,8 @@ use std::io::{self, Write, Read};
 use std::fmt;
 
 use crate::traits;
+use crate::logging::Trap;
+use crate::archive::Codec;
 use traits::YamlFile;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +17,9 @@ pub struct GlobalConfig {
     pub backups: PathBuf,
     pub snapshots: PathBuf,
     pub log: PathBuf,
+    pub known_hosts: Option<PathBuf>, // default: $HOME/.ssh/known_hosts
+    pub encryption: Option<Secret>,   // passphrase or keyfile unlocking the repository key, none: unencrypted
+    pub allow_plaintext: Option<bool>, // default: false, reads unencrypted data with encryption configured (until `key migrate`)
 }
 
 #[test]
@@ -24,6 +29,9 @@ fn test_global_config_serialize() {
         backups: PathBuf::from("/home/dto/bakcups/"),
         snapshots: PathBuf::from("/etc/rensen/hosts.yml"),
         log: PathBuf::from("/etc/rensen/log"),
+        known_hosts: Some(PathBuf::from("/etc/rensen/known_hosts")),
+        encryption: None,
+        allow_plaintext: None,
     };
 
     let path = PathBuf::from("gc.yml");
@@ -60,10 +68,242 @@ pub struct HostConfig {
     pub user: String,
     pub identifier: String,        // machine addr
     pub port: Option<u16>,         // default: 22
-    pub key: Option<PathBuf>, // default: "$HOME/.ssh/ed25516"
-    pub source: PathBuf,
+    pub key: Option<PathBuf>, // default: "$HOME/.ssh/ed25519"
+    #[serde(deserialize_with = "deserialize_sources")]
+    pub source: Vec<PathBuf>,          // one or more remote paths
     pub destination: PathBuf,
     pub cron_schedule: Option<String>, // defualt `* 0 0 * * * *`
+    pub exclude: Option<Vec<String>>,  // gitignore-style patterns
+    pub include: Option<Vec<String>>,  // re-includes excluded paths
+    pub parallelism: Option<usize>,    // default: 1 (concurrent transfers)
+    pub change_detection: Option<ChangeDetection>, // default: mtime
+    pub delta_threshold: Option<u64>,  // bytes, delta transfer for files at least this large
+    pub delta_block_size: Option<u64>, // default: 1048576
+    pub xattrs: Option<bool>,          // default: false (needs getfattr on the host)
+    pub acls: Option<bool>,            // default: false (needs getfacl on the host)
+    pub auth: Option<AuthMethod>,      // default: key
+    pub secret: Option<Secret>,        // passphrase of the key, or the password
+    pub connect_timeout: Option<u64>,  // secs, default: 30
+    pub timeout: Option<u64>,          // secs, read/write timeout of the session, default: 300 (0: none)
+    pub keepalive_interval: Option<u32>, // secs, default: 60 (0: disabled)
+    pub retries: Option<u32>,          // default: 3, on connection errors
+    pub retry_delay: Option<u64>,      // secs, default: 2, doubled for every retry
+    pub proxy_jump: Option<Vec<JumpHost>>, // jump hosts the host is reached through, in order
+    pub pre_backup: Option<Vec<String>>,  // commands run on the host before copying
+    pub post_backup: Option<Vec<String>>, // commands run on the host after copying
+    pub hook_timeout: Option<u64>,     // secs, default: 600 (per command)
+    pub commands: Option<Vec<CommandSource>>, // sources which are the output of commands
+    pub compression: Option<Codec>,    // default: gzip (gzip, zstd, xz or none)
+    pub compression_level: Option<i32>, // default: the codec's default (gzip/xz: 6, zstd: 3)
+    pub store_compressed_media: Option<bool>, // default: false, media and archives go uncompressed into a plain .tar
+    pub storage: Option<StorageMode>,  // default: archive
+    pub retention: Option<Retention>,  // default: every snapshot is kept
+    pub synthesize_schedule: Option<String>, // cron, synthetic full backups from the record (daemon)
+    pub full_schedule: Option<String>, // cron, full backups (daemon), cron_schedule takes the incrementals
+    pub full_every: Option<u32>,       // a full backup after every N incrementals (daemon)
+    pub diff_schedule: Option<String>, // cron, differential backups (daemon)
+}
+
+/// A source which is the stdout of a command run on the host (like a database dump),
+/// stored in the snapshot as the virtual file `/.commands/<name>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandSource {
+    pub name: String,     // file name, e.g. `pg_dumpall.sql`
+    pub command: String,
+}
+
+/// A bastion host which the connection is tunneled through (ProxyJump)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JumpHost {
+    pub user: String,
+    pub identifier: String,            // machine addr
+    pub port: Option<u16>,             // default: 22
+    pub key: Option<PathBuf>,          // default: "$HOME/.ssh/ed25519"
+    pub auth: Option<AuthMethod>,      // default: key
+    pub secret: Option<Secret>,
+}
+
+impl fmt::Display for JumpHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.identifier, self.port.unwrap_or(22))
+    }
+}
+
+/// How rensen authenticates with the host
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    #[default]
+    Key,      // private key at `key`, encrypted ones are unlocked with the secret
+    Agent,    // identities of the running ssh-agent (SSH_AUTH_SOCK)
+    Password, // the secret is the password of the user
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthMethod::Key      => write!(f, "key"),
+            AuthMethod::Agent    => write!(f, "agent"),
+            AuthMethod::Password => write!(f, "password"),
+        }
+    }
+}
+
+/// Where a secret (passphrase or password) is read from,
+/// so that it is never stored in the host config itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Secret {
+    File(PathBuf), // first line of a file, e.g. /etc/rensen/secrets/myserver
+    Env(String),   // environment variable
+}
+
+impl Secret {
+    pub fn read(&self) -> Result<String, Trap> {
+        match self {
+            Secret::File(path) => {
+                let contents = std::fs::read_to_string(path).map_err(|err| {
+                    Trap::FS(format!("Could not read secret from {:?}: {}", path, err))
+                })?;
+
+                Ok(contents.lines().next().unwrap_or_default().to_string())
+            },
+            Secret::Env(var) => std::env::var(var).map_err(|err| {
+                Trap::Config(format!("Could not read secret from ${}: {}", var, err))
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Secret::File(path) => write!(f, "file:{}", path.display()),
+            Secret::Env(var)   => write!(f, "env:{}", var),
+        }
+    }
+}
+
+#[test]
+fn test_secret() {
+    let host_config: HostConfig = serde_yaml::from_str("user: user\nidentifier: 1.1.1.1\nsource: /etc\ndestination: dest/path\nauth: password\nsecret:\n  env: RENSEN_TEST_SECRET").unwrap();
+    assert_eq!(host_config.auth, Some(AuthMethod::Password));
+    assert_eq!(host_config.secret, Some(Secret::Env(String::from("RENSEN_TEST_SECRET"))));
+
+    std::env::set_var("RENSEN_TEST_SECRET", "hunter2");
+    assert_eq!(host_config.secret.unwrap().read().unwrap(), "hunter2");
+    assert!(Secret::File(PathBuf::from("/nonexistent/secret")).read().is_err());
+}
+
+/// Strategy for deciding if a file has changed since the last backup
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeDetection {
+    #[default]
+    Mtime,     // newer mtime than recorded
+    MtimeSize, // mtime or size differs from recorded
+    Inode,     // mtime, size, ctime or inode differs from recorded
+    Hash,      // content checksum (sha256) differs from recorded
+}
+
+impl fmt::Display for ChangeDetection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChangeDetection::Mtime     => write!(f, "mtime"),
+            ChangeDetection::MtimeSize => write!(f, "mtime_size"),
+            ChangeDetection::Inode     => write!(f, "inode"),
+            ChangeDetection::Hash      => write!(f, "hash"),
+        }
+    }
+}
+
+/// How the contents of the snapshots are stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageMode {
+    #[default]
+    Archive, // a compressed archive per snapshot
+    Dedup,   // content-defined chunks, stored once by hash across all snapshots and hosts
+}
+
+impl fmt::Display for StorageMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageMode::Archive => write!(f, "archive"),
+            StorageMode::Dedup   => write!(f, "dedup"),
+        }
+    }
+}
+
+/// Which snapshots of a host are kept when pruning (grandfather-father-son).
+/// A snapshot is kept if any of the keep rules keeps it, the ones older than max_age
+/// are pruned regardless. The latest snapshot is always kept.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Retention {
+    pub keep_last: Option<usize>,    // the last N snapshots
+    pub keep_daily: Option<usize>,   // the last snapshot of each of the last N days with snapshots
+    pub keep_weekly: Option<usize>,  // .. of each of the last N (ISO) weeks
+    pub keep_monthly: Option<usize>, // .. of each of the last N months
+    pub keep_yearly: Option<usize>,  // .. of each of the last N years
+    pub max_age: Option<u64>,        // days
+}
+
+impl Retention {
+    /// If any of the keep rules is set. Without them every snapshot within max_age is kept.
+    pub fn has_keep_rules(&self) -> bool {
+        self.keep_last.is_some() || self.keep_daily.is_some() || self.keep_weekly.is_some()
+            || self.keep_monthly.is_some() || self.keep_yearly.is_some()
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rules: Vec<String> = [
+            ("last", self.keep_last),
+            ("daily", self.keep_daily),
+            ("weekly", self.keep_weekly),
+            ("monthly", self.keep_monthly),
+            ("yearly", self.keep_yearly),
+        ].iter()
+            .filter_map(|(rule, keep)| keep.map(|keep| format!("{} {}", rule, keep)))
+            .chain(self.max_age.map(|days| format!("max age {}d", days)))
+            .collect();
+
+        match rules.is_empty() {
+            true  => write!(f, "keep all"),
+            false => write!(f, "{}", rules.join(", ")),
+        }
+    }
+}
+
+/// Accepts both a single source path and a list of them,
+/// so that host configs from before multiple sources were supported still works.
+fn deserialize_sources<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Sources {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+
+    match Sources::deserialize(deserializer)? {
+        Sources::One(path) => Ok(vec![path]),
+        Sources::Many(paths) => Ok(paths),
+    }
+}
+
+#[test]
+fn test_deserialize_sources() {
+    let single = "user: user\nidentifier: 1.1.1.1\nport: 22\nkey: ~/.ssh/testkey\nsource: /etc\ndestination: dest/path\ncron_schedule: \"* 0 0 * * * *\"";
+    let host_config: HostConfig = serde_yaml::from_str(single).unwrap();
+    assert_eq!(host_config.source, vec![PathBuf::from("/etc")]);
+
+    let many = "user: user\nidentifier: 1.1.1.1\nport: 22\nkey: ~/.ssh/testkey\nsource: [/etc, /home, /var/lib/app]\ndestination: dest/path\ncron_schedule: \"* 0 0 * * * *\"";
+    let host_config: HostConfig = serde_yaml::from_str(many).unwrap();
+    assert_eq!(host_config.source, vec![PathBuf::from("/etc"), PathBuf::from("/home"), PathBuf::from("/var/lib/app")]);
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,7 +323,7 @@ impl HostConfig {
         identifier: String,
         port: u16,
         key: PathBuf,
-        source: PathBuf,
+        source: Vec<PathBuf>,
         destination: PathBuf,
         cron_schedule: String
         ) -> Self {
@@ -95,6 +335,35 @@ impl HostConfig {
             source,
             destination,
             cron_schedule: Some(cron_schedule),
+            exclude: None,
+            include: None,
+            parallelism: None,
+            change_detection: None,
+            delta_threshold: None,
+            delta_block_size: None,
+            xattrs: None,
+            acls: None,
+            auth: None,
+            secret: None,
+            connect_timeout: None,
+            timeout: None,
+            keepalive_interval: None,
+            retries: None,
+            retry_delay: None,
+            proxy_jump: None,
+            pre_backup: None,
+            post_backup: None,
+            hook_timeout: None,
+            commands: None,
+            compression: None,
+            compression_level: None,
+            store_compressed_media: None,
+            storage: None,
+            retention: None,
+            synthesize_schedule: None,
+            full_schedule: None,
+            full_every: None,
+            diff_schedule: None,
         }
     }
 }
@@ -103,17 +372,43 @@ impl fmt::Display for HostConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "addr: {}\nuser: {}\nport: {}\nkey: {}\nsource: {}\ndestination: {}\ncron_schedule: {}",
+            "addr: {}\nuser: {}\nport: {}\nkey: {}\nsource: {}\ndestination: {}\ncron_schedule: {}\nexclude: {}\ninclude: {}\nparallelism: {}\nchange_detection: {}\ndelta_threshold: {}\nxattrs: {}\nacls: {}\nauth: {}\nsecret: {}\nconnect_timeout: {}s\ntimeout: {}s\nkeepalive_interval: {}s\nretries: {}\nproxy_jump: {}\npre_backup: {}\npost_backup: {}\ncommands: {}\ncompression: {} (level {})\nstore_compressed_media: {}\nstorage: {}\nretention: {}\nsynthesize_schedule: {}\nfull_schedule: {}\nfull_every: {}\ndiff_schedule: {}",
             self.identifier,
             self.user,
             self.port.unwrap_or(22),
             self.key
                 .as_ref()
                 .map(|path| path.display().to_string())
-                .unwrap_or_else(|| "$HOME/.ssh/ed25516".to_string()),
-            self.source.display(),
+                .unwrap_or_else(|| "$HOME/.ssh/ed25519".to_string()),
+            self.source.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "),
             self.destination.display(),
             self.cron_schedule.as_ref().unwrap(),
+            self.exclude.as_ref().map(|patterns| patterns.join(", ")).unwrap_or_default(),
+            self.include.as_ref().map(|patterns| patterns.join(", ")).unwrap_or_default(),
+            self.parallelism.unwrap_or(1),
+            self.change_detection.unwrap_or_default(),
+            self.delta_threshold.map(|threshold| threshold.to_string()).unwrap_or_else(|| "disabled".to_string()),
+            self.xattrs.unwrap_or(false),
+            self.acls.unwrap_or(false),
+            self.auth.unwrap_or_default(),
+            self.secret.as_ref().map(|secret| secret.to_string()).unwrap_or_else(|| "none".to_string()),
+            self.connect_timeout.unwrap_or(30),
+            self.timeout.unwrap_or(300),
+            self.keepalive_interval.unwrap_or(60),
+            self.retries.unwrap_or(3),
+            self.proxy_jump.iter().flatten().map(|jump| jump.to_string()).collect::<Vec<_>>().join(" -> "),
+            self.pre_backup.as_ref().map(|commands| commands.join("; ")).unwrap_or_default(),
+            self.post_backup.as_ref().map(|commands| commands.join("; ")).unwrap_or_default(),
+            self.commands.iter().flatten().map(|command| command.name.clone()).collect::<Vec<_>>().join(", "),
+            self.compression.unwrap_or_default(),
+            self.compression_level.unwrap_or(self.compression.unwrap_or_default().default_level()),
+            self.store_compressed_media.unwrap_or(false),
+            self.storage.unwrap_or_default(),
+            self.retention.clone().unwrap_or_default(),
+            self.synthesize_schedule.clone().unwrap_or_default(),
+            self.full_schedule.clone().unwrap_or_default(),
+            self.full_every.map(|full_every| format!("{} incrementals", full_every)).unwrap_or_default(),
+            self.diff_schedule.clone().unwrap_or_default(),
         )
     }
 }
@@ -0,0 +1,600 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::{Serialize, Deserialize};
+use fxhash::FxHashMap;
+use sha2::Sha256;
+use hmac::{Hmac, Mac};
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chacha20poly1305::{XChaCha20Poly1305, Key, XNonce, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{EncryptorBE32, DecryptorBE32};
+
+use crate::logging::Trap;
+use crate::config::GlobalConfig;
+use crate::utils::get_datetime;
+
+/// Starts every encrypted archive and record, followed by the key id and the nonce prefix
+const MAGIC: &[u8; 8] = b"RENSENC\x01";
+const NONCE_PREFIX_SIZE: usize = 19; // XChaCha20 nonce (24) minus the STREAM counter and last flag (5)
+const HEADER_SIZE: usize = MAGIC.len() + 4 + NONCE_PREFIX_SIZE;
+
+/// Plaintext size of every chunk but the last one
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// The unlocked keys of the repository by id. New data is encrypted with the current key,
+/// earlier keys are kept after a rotation for the data that was encrypted with them.
+pub struct KeyRing {
+    current: u32,
+    keys: FxHashMap<u32, Key>,
+}
+
+impl KeyRing {
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Name of a chunk with data: its HMAC-SHA256 (hex), so the names of the chunks tell
+    /// nothing about their contents without the key. Keyed by a key derived from the first key
+    /// of the repository, for chunks to keep their names (and deduplicate) after a rotation.
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        let first = self.keys.keys().min().and_then(|id| self.keys.get(id));
+        let chunk_key = hmac_sha256(first.map(|key| key.as_slice()).unwrap_or_default(), b"rensen chunk id");
+
+        hmac_sha256(&chunk_key, data).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn key(&self, id: u32) -> io::Result<&Key> {
+        self.keys.get(&id).ok_or(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Encrypted with unknown key {}", id)
+        ))
+    }
+}
+
+/// The key file of the repository. Data keys are random and only stored wrapped, by a key
+/// derived (argon2id) from the passphrase or keyfile.
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    current: u32,
+    keys: Vec<WrappedKey>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WrappedKey {
+    id: u32,
+    created: String,
+    salt: String,  // base64
+    nonce: String, // base64
+    key: String,   // base64, the data key encrypted with the derived key
+}
+
+/// HMAC-SHA256 of data under key
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Keys the archives and records were encrypted with, installed once unlocked.
+/// The `JsonFile` impl of Record has no way of being handed the keys, so they are process wide.
+static KEYRING: RwLock<Option<Arc<KeyRing>>> = RwLock::new(None);
+
+/// If encryption is configured, and if data written before it was set up is still read as it is
+/// (see `configure`). Unencrypted data is not authenticated, so anyone with write access to
+/// the backups could swap it in.
+static ENCRYPTION_CONFIGURED: AtomicBool = AtomicBool::new(false);
+static PLAINTEXT_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Refuses unencrypted records, archives and chunks from now on if encryption is configured,
+/// unless `allow_plaintext` is set for a repository which is not migrated yet (see `migrate`).
+pub fn configure(global_config: &GlobalConfig) {
+    ENCRYPTION_CONFIGURED.store(global_config.encryption.is_some(), Ordering::Relaxed);
+    PLAINTEXT_ALLOWED.store(global_config.allow_plaintext.unwrap_or(false), Ordering::Relaxed);
+}
+
+/// Fails if unencrypted data is refused: when encryption is configured or keys are installed,
+/// and plaintext is not explicitly allowed.
+pub fn check_plaintext() -> io::Result<()> {
+    let encrypted = ENCRYPTION_CONFIGURED.load(Ordering::Relaxed) || installed().is_some();
+    if encrypted && !PLAINTEXT_ALLOWED.load(Ordering::Relaxed) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Data is not encrypted while encryption is configured, run `key migrate` to encrypt data written before it was set up"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Installs the keys archives and records are encrypted with from now on.
+/// None leaves new data unencrypted.
+pub fn install(keys: Option<KeyRing>) {
+    if let Ok(mut keyring) = KEYRING.write() {
+        *keyring = keys.map(Arc::new);
+    }
+}
+
+pub fn installed() -> Option<Arc<KeyRing>> {
+    KEYRING.read().ok().and_then(|keyring| keyring.clone())
+}
+
+/// The key file, in the root of the backups
+pub fn key_path(global_config: &GlobalConfig) -> PathBuf {
+    global_config.backups.join(".rensen.key")
+}
+
+/// Unlocks the repository key with the secret of the global config, if encryption is configured.
+pub fn unlock_repository(global_config: &GlobalConfig) -> Result<Option<KeyRing>, Trap> {
+    let secret = match &global_config.encryption {
+        Some(secret) => secret.read()?,
+        None => return Ok(None),
+    };
+
+    unlock(&key_path(global_config), &secret).map(Some)
+}
+
+/// Creates the key file at path with a new random key, wrapped by secret.
+pub fn init_key(path: &Path, secret: &str) -> Result<(), Trap> {
+    if path.exists() {
+        return Err(Trap::InvalidInput(format!("Repository key {:?} is already initialised", path)));
+    }
+
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    let key_file = KeyFile {
+        current: 1,
+        keys: vec![wrap_key(1, &key, secret)?],
+    };
+
+    write_key_file(path, &key_file)
+}
+
+/// Unwraps every key of the key file at path with secret.
+pub fn unlock(path: &Path, secret: &str) -> Result<KeyRing, Trap> {
+    let key_file = read_key_file(path)?;
+
+    let mut keys = FxHashMap::default();
+    for wrapped in &key_file.keys {
+        keys.insert(wrapped.id, unwrap_key(wrapped, secret)?);
+    }
+
+    if !keys.contains_key(&key_file.current) {
+        return Err(Trap::Crypto(format!("Current key {} is missing from {:?}", key_file.current, path)));
+    }
+
+    Ok(KeyRing { current: key_file.current, keys })
+}
+
+/// Adds a new key which becomes the current one, and wraps all keys by new_secret
+/// (which may be the same as secret). Data encrypted earlier stays readable.
+/// Returns the id of the new key.
+pub fn rotate_key(path: &Path, secret: &str, new_secret: &str) -> Result<u32, Trap> {
+    let key_file = read_key_file(path)?;
+    let keyring = unlock(path, secret)?;
+
+    let id = keyring.keys.keys().max().copied().unwrap_or(0) + 1;
+    let new_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+
+    let mut keys = Vec::new();
+    for existing in key_file.keys {
+        // The creation time of the existing keys is kept
+        let mut wrapped = wrap_key(existing.id, &keyring.keys[&existing.id], new_secret)?;
+        wrapped.created = existing.created;
+        keys.push(wrapped);
+    }
+    keys.push(wrap_key(id, &new_key, new_secret)?);
+
+    write_key_file(path, &KeyFile { current: id, keys })?;
+    Ok(id)
+}
+
+fn derive_key(secret: &str, salt: &[u8]) -> Result<Key, Trap> {
+    let mut derived = Key::default();
+    Argon2::default().hash_password_into(secret.as_bytes(), salt, &mut derived).map_err(|err| {
+        Trap::Crypto(format!("Could not derive key: {}", err))
+    })?;
+
+    Ok(derived)
+}
+
+fn wrap_key(id: u32, key: &Key, secret: &str) -> Result<WrappedKey, Trap> {
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = XNonce::default();
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = XChaCha20Poly1305::new(&derive_key(secret, &salt)?);
+    let wrapped = cipher.encrypt(&nonce, Payload { msg: key.as_slice(), aad: &id.to_be_bytes() }).map_err(|_| {
+        Trap::Crypto(format!("Could not wrap key {}", id))
+    })?;
+
+    Ok(WrappedKey {
+        id,
+        created: get_datetime(),
+        salt: STANDARD.encode(salt),
+        nonce: STANDARD.encode(nonce),
+        key: STANDARD.encode(wrapped),
+    })
+}
+
+fn unwrap_key(wrapped: &WrappedKey, secret: &str) -> Result<Key, Trap> {
+    let decode = |value: &str| STANDARD.decode(value).map_err(|err| {
+        Trap::Crypto(format!("Key {} is malformed: {}", wrapped.id, err))
+    });
+
+    let salt = decode(&wrapped.salt)?;
+    let nonce = decode(&wrapped.nonce)?;
+    if nonce.len() != XNonce::default().len() {
+        return Err(Trap::Crypto(format!("Key {} is malformed: invalid nonce", wrapped.id)));
+    }
+
+    let cipher = XChaCha20Poly1305::new(&derive_key(secret, &salt)?);
+    let key = cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: &decode(&wrapped.key)?, aad: &wrapped.id.to_be_bytes() })
+        .map_err(|_| Trap::Crypto(format!("Could not unlock key {}, wrong passphrase or keyfile?", wrapped.id)))?;
+
+    if key.len() != Key::default().len() {
+        return Err(Trap::Crypto(format!("Key {} is malformed: invalid length", wrapped.id)));
+    }
+
+    Ok(*Key::from_slice(&key))
+}
+
+fn read_key_file(path: &Path) -> Result<KeyFile, Trap> {
+    let contents = fs::read_to_string(path).map_err(|err| {
+        Trap::FS(format!("Could not read repository key {:?}: {}", path, err))
+    })?;
+
+    serde_json::from_str(&contents).map_err(|err| {
+        Trap::Deserialize(format!("Could not deserialize repository key {:?}: {}", path, err))
+    })
+}
+
+/// Writes the key file next to path and moves it into place, readable by the owner only
+fn write_key_file(path: &Path, key_file: &KeyFile) -> Result<(), Trap> {
+    let json = serde_json::to_string_pretty(key_file).map_err(|err| {
+        Trap::Serialize(format!("Could not serialize repository key: {}", err))
+    })?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| {
+            Trap::FS(format!("Could not create directory {:?}: {}", parent, err))
+        })?;
+    }
+
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let written = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(json.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    written.map_err(|err| {
+        let _ = fs::remove_file(&temp_path);
+        Trap::FS(format!("Could not write repository key {:?}: {}", path, err))
+    })
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Encrypted data is corrupt or was tampered with")
+}
+
+/// Encrypts everything written to it with the current key of keys (XChaCha20-Poly1305 STREAM).
+/// Data is sealed in chunks, the last one marked as such, so that reordered and truncated
+/// data is detected as well. `finish` has to be called to seal the last chunk.
+pub struct Encryptor<W: Write> {
+    inner: W,
+    stream: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    pub fn new(mut inner: W, keys: &KeyRing) -> io::Result<Self> {
+        let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&keys.current.to_be_bytes());
+        header.extend_from_slice(&nonce_prefix);
+        inner.write_all(&header)?;
+
+        let cipher = XChaCha20Poly1305::new(keys.key(keys.current)?);
+        Ok(Encryptor {
+            inner,
+            stream: Some(EncryptorBE32::from_aead(cipher, (&nonce_prefix).into())),
+            header,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    /// Seals the last chunk, returning the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let stream = self.stream.take().ok_or(corrupt())?;
+        let sealed = stream.encrypt_last(Payload { msg: &self.buffer, aad: &self.header }).map_err(|_| corrupt())?;
+        self.inner.write_all(&sealed)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            // A full chunk is only sealed once more data follows, the last one is sealed by finish
+            if self.buffer.len() == CHUNK_SIZE {
+                let stream = self.stream.as_mut().ok_or(corrupt())?;
+                let sealed = stream.encrypt_next(Payload { msg: &self.buffer, aad: &self.header }).map_err(|_| corrupt())?;
+                self.inner.write_all(&sealed)?;
+                self.buffer.clear();
+            }
+
+            let n = (CHUNK_SIZE - self.buffer.len()).min(buf.len() - written);
+            self.buffer.extend_from_slice(&buf[written..written + n]);
+            written += n;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts what was written by an Encryptor, with the key of keys it was encrypted with
+pub struct Decryptor<R: Read> {
+    inner: R,
+    stream: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    sealed: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> Decryptor<R> {
+    pub fn new(mut inner: R, keys: &KeyRing) -> io::Result<Self> {
+        let mut header = vec![0; HEADER_SIZE];
+        inner.read_exact(&mut header)?;
+        if !is_encrypted(&header) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Data is not encrypted"));
+        }
+
+        let id = u32::from_be_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+        let nonce_prefix = &header[MAGIC.len() + 4..];
+
+        let cipher = XChaCha20Poly1305::new(keys.key(id)?);
+        let stream = DecryptorBE32::from_aead(cipher, nonce_prefix.into());
+
+        Ok(Decryptor {
+            inner,
+            stream: Some(stream),
+            header,
+            sealed: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE + 1),
+            plaintext: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Opens the next chunk. One byte past a full chunk is read ahead,
+    /// to know whether it is the last one.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let target = CHUNK_SIZE + TAG_SIZE + 1;
+        let mut buffer = [0; 8192];
+        while self.sealed.len() < target {
+            let n = self.inner.read(&mut buffer[..(target - self.sealed.len()).min(8192)])?;
+            if n == 0 {
+                break;
+            }
+            self.sealed.extend_from_slice(&buffer[..n]);
+        }
+
+        self.plaintext = if self.sealed.len() == target {
+            let stream = self.stream.as_mut().ok_or(corrupt())?;
+            let plaintext = stream.decrypt_next(Payload { msg: &self.sealed[..target - 1], aad: &self.header }).map_err(|_| corrupt())?;
+            self.sealed.drain(..target - 1);
+            plaintext
+        } else {
+            let stream = self.stream.take().ok_or(corrupt())?;
+            stream.decrypt_last(Payload { msg: &self.sealed, aad: &self.header }).map_err(|_| corrupt())?
+        };
+
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.stream.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+
+        let n = (self.plaintext.len() - self.position).min(buf.len());
+        buf[..n].copy_from_slice(&self.plaintext[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Reader of data which may be encrypted (archives and records written before encryption
+/// was set up are not), decrypted with the installed keys if it is. Unencrypted data is
+/// refused once encryption is configured, see `check_plaintext`.
+pub fn decrypting_reader<R: Read + 'static>(inner: R) -> io::Result<Box<dyn Read>> {
+    let mut inner = BufReader::new(inner);
+    if !is_encrypted(inner.fill_buf()?) {
+        check_plaintext()?;
+        return Ok(Box::new(inner));
+    }
+
+    let keys = installed().ok_or(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "Data is encrypted, but the repository key is not unlocked"
+    ))?;
+
+    Ok(Box::new(Decryptor::new(inner, &keys)?))
+}
+
+/// Encrypts every file in the backups which is not encrypted yet, written before encryption
+/// was set up, in place. Chunks keep their names. Returns the number of files and bytes encrypted.
+/// Backups must not be running meanwhile.
+pub fn migrate(global_config: &GlobalConfig) -> Result<(usize, u64), Trap> {
+    let keys = installed().ok_or(Trap::Crypto(String::from("The repository key is not unlocked")))?;
+    let mut migrated = (0, 0);
+
+    let mut dirs = vec![global_config.backups.clone()];
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(&dir).map_err(|err| Trap::FS(format!("Could not read directory {:?}: {}", dir, err)))?;
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            // The key file, locks and files being written
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !file_type.is_file() || name == ".rensen.key" || name.ends_with(".lock") || name.ends_with(".tmp") {
+                continue;
+            }
+
+            let mut magic = Vec::new();
+            File::open(&path)
+                .and_then(|file| file.take(MAGIC.len() as u64).read_to_end(&mut magic))
+                .map_err(|err| Trap::FS(format!("Could not read {:?}: {}", path, err)))?;
+
+            if is_encrypted(&magic) {
+                continue;
+            }
+
+            let temp_path = PathBuf::from(format!("{}.{}.migrate.tmp", path.display(), std::process::id()));
+            let written = File::open(&path)
+                .and_then(|mut file| {
+                    let mut encryptor = Encryptor::new(File::create(&temp_path)?, &keys)?;
+                    let size = io::copy(&mut file, &mut encryptor)?;
+                    encryptor.finish()?.sync_all()?;
+                    Ok(size)
+                })
+                .and_then(|size| fs::rename(&temp_path, &path).map(|_| size));
+
+            match written {
+                Ok(size) => {
+                    migrated.0 += 1;
+                    migrated.1 += size;
+                },
+                Err(err) => {
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(Trap::Crypto(format!("Could not encrypt {:?}: {}", path, err)));
+                },
+            }
+        }
+    }
+
+    Ok(migrated)
+}
+
+#[test]
+fn test_encryption() {
+    let mut keys = KeyRing { current: 1, keys: FxHashMap::default() };
+    keys.keys.insert(1, XChaCha20Poly1305::generate_key(&mut OsRng));
+
+    // Empty, within one chunk, exactly one chunk, and across chunks
+    for size in [0, 100, CHUNK_SIZE, CHUNK_SIZE * 2 + 7] {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+
+        let mut encryptor = Encryptor::new(Vec::new(), &keys).unwrap();
+        encryptor.write_all(&data).unwrap();
+        let sealed = encryptor.finish().unwrap();
+        assert!(is_encrypted(&sealed));
+
+        let mut opened = Vec::new();
+        Decryptor::new(&sealed[..], &keys).unwrap().read_to_end(&mut opened).unwrap();
+        assert_eq!(opened, data, "size {}", size);
+
+        // Tampered with
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(Decryptor::new(&tampered[..], &keys).unwrap().read_to_end(&mut Vec::new()).is_err());
+
+        // Truncated at a chunk boundary
+        if size > CHUNK_SIZE {
+            let truncated = &sealed[..HEADER_SIZE + CHUNK_SIZE + TAG_SIZE];
+            assert!(Decryptor::new(truncated, &keys).unwrap().read_to_end(&mut Vec::new()).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_hmac_sha256() {
+    use sha2::Digest;
+
+    let hex = |bytes: [u8; 32]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+
+    // RFC 4231, test cases 2 and 6 (key longer than a block)
+    assert_eq!(hex(hmac_sha256(b"Jefe", b"what do ya want for nothing?")), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    assert_eq!(hex(hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+
+    // Chunk names depend on the first key only, and are not the plain hash
+    let mut keys = KeyRing { current: 1, keys: FxHashMap::default() };
+    keys.keys.insert(1, XChaCha20Poly1305::generate_key(&mut OsRng));
+    let chunk_id = keys.chunk_id(b"data");
+    assert_ne!(chunk_id, format!("{:x}", Sha256::digest(b"data")));
+
+    keys.keys.insert(2, XChaCha20Poly1305::generate_key(&mut OsRng));
+    keys.current = 2;
+    assert_eq!(keys.chunk_id(b"data"), chunk_id);
+
+    let mut other = KeyRing { current: 1, keys: FxHashMap::default() };
+    other.keys.insert(1, XChaCha20Poly1305::generate_key(&mut OsRng));
+    assert_ne!(other.chunk_id(b"data"), chunk_id);
+}
+
+#[test]
+fn test_key_rotation() {
+    let dir = std::env::temp_dir().join(format!("rensen-test-key-{}", std::process::id()));
+    let path = dir.join(".rensen.key");
+    let _ = fs::remove_dir_all(&dir);
+
+    init_key(&path, "passphrase").unwrap();
+    assert!(init_key(&path, "passphrase").is_err());
+    assert!(unlock(&path, "wrong").is_err());
+
+    let keys = unlock(&path, "passphrase").unwrap();
+    let mut encryptor = Encryptor::new(Vec::new(), &keys).unwrap();
+    encryptor.write_all(b"before rotation").unwrap();
+    let sealed = encryptor.finish().unwrap();
+
+    assert_eq!(rotate_key(&path, "passphrase", "new passphrase").unwrap(), 2);
+    assert!(unlock(&path, "passphrase").is_err());
+
+    // Data of the earlier key is still readable
+    let keys = unlock(&path, "new passphrase").unwrap();
+    assert_eq!(keys.current(), 2);
+    let mut opened = String::new();
+    Decryptor::new(&sealed[..], &keys).unwrap().read_to_string(&mut opened).unwrap();
+    assert_eq!(opened, "before rotation");
+
+    let _ = fs::remove_dir_all(&dir);
+}
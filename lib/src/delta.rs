@@ -0,0 +1,232 @@
+use std::io::{self, Read};
+use fxhash::FxHashMap;
+use sha2::{Digest, Sha256};
+
+use crate::utils::read_block;
+
+/// Generator polynomial of the CRC-32 of POSIX `cksum`
+const POLY: u32 = 0x04c11db7;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// Bytes of the base read at a time while looking for blocks
+const READ_SIZE: usize = 1024 * 1024;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80000000 != 0 { (crc << 1) ^ POLY } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc_update(crc: u32, byte: u8) -> u32 {
+    (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+}
+
+/// The raw CRC (no length appended, not complemented) of data
+fn crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| crc_update(crc, byte))
+}
+
+/// Completes the raw CRC of len bytes to their `cksum`
+fn finish(mut crc: u32, len: usize) -> u32 {
+    let mut remaining = len;
+    while remaining > 0 {
+        crc = crc_update(crc, remaining as u8);
+        remaining >>= 8;
+    }
+    !crc
+}
+
+/// The checksum of data as printed by POSIX `cksum`
+pub fn cksum(data: &[u8]) -> u32 {
+    finish(crc(data), data.len())
+}
+
+/// Signature of a block of the remote file, from `split --filter=cksum` and `split --filter=sha256sum`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSignature {
+    pub weak: u32,      // cksum, which can be rolled over the base
+    pub strong: String, // sha256 (hex), confirming a match
+    pub size: usize,
+}
+
+/// Pairs the outputs of `cksum` and `sha256sum` for every block, None if they do not line up
+pub fn parse_signatures(cksums: &str, sha256sums: &str) -> Option<Vec<BlockSignature>> {
+    let cksums: Vec<&str> = cksums.lines().filter(|line| !line.trim().is_empty()).collect();
+    let sha256sums: Vec<&str> = sha256sums.lines().filter(|line| !line.trim().is_empty()).collect();
+    if cksums.len() != sha256sums.len() {
+        return None;
+    }
+
+    cksums.iter().zip(sha256sums).map(|(cksum, sha256sum)| {
+        let mut fields = cksum.split_whitespace();
+        Some(BlockSignature {
+            weak: fields.next()?.parse().ok()?,
+            size: fields.next()?.parse().ok()?,
+            strong: sha256sum.split_whitespace().next()?.to_string(),
+        })
+    }).collect()
+}
+
+/// CRC of a window of the base, rolled along it a byte at a time, for the blocks of one size
+struct Roller {
+    len: usize,
+    outgoing: [u32; 256],                // the CRC of a byte followed by len zero bytes, to take it out of the window
+    blocks: FxHashMap<u32, Vec<usize>>,  // cksum: signatures of blocks of len bytes
+    next: Option<(u64, u32)>,            // offset and raw CRC of the window after the current one
+}
+
+impl Roller {
+    fn new(len: usize) -> Self {
+        // Linear in the byte, so only the CRC of every bit has to be carried through the zeros
+        let mut bits = [0u32; 8];
+        for (bit, crc) in bits.iter_mut().enumerate() {
+            *crc = (0..len).fold(crc_update(0, 1 << bit), |crc, _| crc_update(crc, 0));
+        }
+
+        let mut outgoing = [0u32; 256];
+        for (byte, crc) in outgoing.iter_mut().enumerate() {
+            *crc = (0..8).filter(|bit| byte & (1 << bit) != 0).fold(0, |crc, bit| crc ^ bits[bit]);
+        }
+
+        Roller { len, outgoing, blocks: FxHashMap::default(), next: None }
+    }
+}
+
+/// Finds the blocks of the remote file in base, in the manner of rsync: the cksum of a window
+/// of every block size is rolled over every offset of base, and a window with the cksum of a block
+/// is confirmed to be it by its sha256. Blocks are found wherever they moved to in base.
+///
+/// Returns for every signature the offset in base of a block with its contents, if any.
+pub fn match_blocks<R: Read>(base: &mut R, signatures: &[BlockSignature]) -> io::Result<Vec<Option<u64>>> {
+    let mut matches = vec![None; signatures.len()];
+
+    // The last block is shorter, and looked for on its own
+    let mut rollers: Vec<Roller> = Vec::new();
+    for (i, signature) in signatures.iter().enumerate().filter(|(_, signature)| signature.size > 0) {
+        let roller = match rollers.iter().position(|roller| roller.len == signature.size) {
+            Some(position) => &mut rollers[position],
+            None => {
+                rollers.push(Roller::new(signature.size));
+                rollers.last_mut().unwrap()
+            },
+        };
+        roller.blocks.entry(signature.weak).or_default().push(i);
+    }
+
+    let (min_len, max_len) = match (rollers.iter().map(|roller| roller.len).min(), rollers.iter().map(|roller| roller.len).max()) {
+        (Some(min_len), Some(max_len)) => (min_len, max_len),
+        _ => return Ok(matches),
+    };
+
+    // data holds base from the offset start on, at least the longest window after offset (and a byte) until EOF
+    let mut data: Vec<u8> = Vec::new();
+    let mut start = 0u64;
+    let mut eof = false;
+    let mut offset = 0u64;
+
+    loop {
+        let position = (offset - start) as usize;
+        if position + max_len >= data.len() && !eof {
+            data.drain(..position);
+            start = offset;
+
+            let filled = data.len();
+            data.resize(filled + READ_SIZE.max(max_len), 0);
+            let bytes_read = read_block(base, &mut data[filled..])?;
+            data.truncate(filled + bytes_read);
+            eof = bytes_read == 0;
+            continue;
+        }
+
+        if position + min_len > data.len() {
+            break;
+        }
+
+        for roller in rollers.iter_mut() {
+            if position + roller.len > data.len() {
+                roller.next = None;
+                continue;
+            }
+
+            let window = &data[position..position + roller.len];
+            let crc = match roller.next {
+                Some((next, crc)) if next == offset => crc,
+                _ => crc(window),
+            };
+
+            if let Some(blocks) = roller.blocks.get(&finish(crc, roller.len)) {
+                if blocks.iter().any(|&i| matches[i].is_none()) {
+                    let strong = format!("{:x}", Sha256::digest(window));
+                    for &i in blocks.iter().filter(|&&i| signatures[i].strong == strong) {
+                        matches[i].get_or_insert(offset);
+                    }
+                }
+            }
+
+            roller.next = data.get(position + roller.len).map(|&incoming| {
+                (offset + 1, crc_update(crc, incoming) ^ roller.outgoing[data[position] as usize])
+            });
+        }
+
+        offset += 1;
+    }
+
+    Ok(matches)
+}
+
+#[test]
+fn test_cksum() {
+    // The check value of POSIX cksum
+    assert_eq!(cksum(b"123456789"), 930766865);
+    assert_eq!(cksum(b""), 4294967295);
+
+    // Rolled windows have the checksum of the window
+    let data: Vec<u8> = (0..200u32).map(|i| (i * 7 + i / 3) as u8).collect();
+    let roller = Roller::new(16);
+    let mut rolled = crc(&data[..16]);
+    for offset in 0..data.len() - 16 {
+        assert_eq!(finish(rolled, 16), cksum(&data[offset..offset + 16]));
+        rolled = crc_update(rolled, data[offset + 16]) ^ roller.outgoing[data[offset] as usize];
+    }
+}
+
+#[test]
+fn test_match_blocks() {
+    let signature = |block: &[u8]| BlockSignature { weak: cksum(block), strong: format!("{:x}", Sha256::digest(block)), size: block.len() };
+
+    // Data inserted at the start shifts every block of the base
+    let base: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8 ^ (i / 251) as u8).collect();
+    let mut remote = b"inserted".to_vec();
+    remote.extend_from_slice(&base);
+    remote.extend_from_slice(b"appended");
+
+    let signatures: Vec<BlockSignature> = remote.chunks(1000).map(signature).collect();
+    let matches = match_blocks(&mut &base[..], &signatures).unwrap();
+
+    for (i, matched) in matches.iter().enumerate() {
+        let block = &remote[i * 1000..(i * 1000 + 1000).min(remote.len())];
+        match matched {
+            Some(offset) => assert_eq!(&base[*offset as usize..*offset as usize + block.len()], block),
+            None => assert!(i == 0 || i == signatures.len() - 1, "block {} was not found", i),
+        }
+    }
+    assert_eq!(matches.iter().filter(|matched| matched.is_some()).count(), signatures.len() - 2);
+
+    // As printed by split
+    let last = signatures.last().unwrap();
+    let cksums = format!("{} {}\n{} {}\n", signatures[1].weak, signatures[1].size, last.weak, last.size);
+    let sha256sums = format!("{}  -\n{}  -\n", signatures[1].strong, last.strong);
+    assert_eq!(parse_signatures(&cksums, &sha256sums).unwrap(), vec![signatures[1].clone(), last.clone()]);
+    assert!(parse_signatures(&cksums, "").is_none());
+}
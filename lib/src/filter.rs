@@ -0,0 +1,70 @@
+use std::path::Path;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::logging::Trap;
+use crate::config::HostConfig;
+
+/// Gitignore-style matcher for the exclude/include patterns of a host.
+/// Include patterns are treated as negated (`!pattern`) rules, so they
+/// re-include paths that would otherwise be excluded.
+pub struct PathFilter {
+    matcher: Gitignore,
+}
+
+impl PathFilter {
+    pub fn from(host_config: &HostConfig) -> Result<Self, Trap> {
+        let mut builder = GitignoreBuilder::new("");
+
+        for pattern in host_config.exclude.iter().flatten() {
+            builder.add_line(None, pattern).map_err(|err| {
+                Trap::Config(format!("Invalid exclude pattern `{}`: {}", pattern, err))
+            })?;
+        }
+
+        for pattern in host_config.include.iter().flatten() {
+            builder.add_line(None, &format!("!{}", pattern.trim_start_matches('!'))).map_err(|err| {
+                Trap::Config(format!("Invalid include pattern `{}`: {}", pattern, err))
+            })?;
+        }
+
+        let matcher = builder.build().map_err(|err| {
+            Trap::Config(format!("Could not build path filter: {}", err))
+        })?;
+
+        Ok(PathFilter { matcher })
+    }
+
+    /// Checks if `path` (relative to the source root) is excluded,
+    /// either by itself or by one of its parent directories.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.matcher.is_empty() || path.as_os_str().is_empty() {
+            return false;
+        }
+
+        self.matcher.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+}
+
+#[test]
+fn test_path_filter() {
+    let mut host_config = HostConfig::from(
+        String::from("user"),
+        String::from("1.1.1.1"),
+        22,
+        "~/.ssh/testkey".into(),
+        vec!["/home/user".into()],
+        "dest/path".into(),
+        String::from("* 0 0 * * * *")
+    );
+
+    host_config.exclude = Some(vec![String::from("node_modules/"), String::from(".cache"), String::from("*.log")]);
+    host_config.include = Some(vec![String::from("keep.log")]);
+
+    let filter = PathFilter::from(&host_config).unwrap();
+    assert!(filter.is_excluded(Path::new("project/node_modules"), true));
+    assert!(filter.is_excluded(Path::new("project/node_modules/pkg/index.js"), false));
+    assert!(filter.is_excluded(Path::new(".cache/thumbnails/a.png"), false));
+    assert!(filter.is_excluded(Path::new("var/debug.log"), false));
+    assert!(!filter.is_excluded(Path::new("var/keep.log"), false));
+    assert!(!filter.is_excluded(Path::new("project/src/main.rs"), false));
+}
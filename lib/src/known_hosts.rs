@@ -0,0 +1,123 @@
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use ssh2::{Session, CheckResult, HashType, KnownHostFileKind};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+
+use crate::logging::Trap;
+use crate::config::GlobalConfig;
+
+/// Result of checking the host key of a session against the known hosts
+#[derive(Debug, PartialEq)]
+pub enum HostKeyCheck {
+    Match,
+    NotFound,
+}
+
+/// The known_hosts file host keys are pinned in. Either the rensen-managed file
+/// set in the global config, or the user's `~/.ssh/known_hosts`.
+pub fn known_hosts_path(global_config: &GlobalConfig) -> PathBuf {
+    match &global_config.known_hosts {
+        Some(path) => path.clone(),
+        None => PathBuf::from(std::env::var("HOME").unwrap_or_default())
+            .join(".ssh")
+            .join("known_hosts"),
+    }
+}
+
+/// Name of the host as written in known_hosts, `[identifier]:port` for non-standard ports
+pub fn known_host_name(identifier: &str, port: u16) -> String {
+    match port {
+        22 => identifier.to_string(),
+        _ => format!("[{}]:{}", identifier, port),
+    }
+}
+
+/// SHA256 fingerprint of the host key, formatted like OpenSSH does: `SHA256:<base64>`
+pub fn fingerprint(sess: &Session) -> Option<String> {
+    sess.host_key_hash(HashType::Sha256)
+        .map(format_fingerprint)
+}
+
+fn format_fingerprint(hash: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(hash))
+}
+
+#[test]
+fn test_known_hosts_format() {
+    assert_eq!(known_host_name("192.168.1.2", 22), "192.168.1.2");
+    assert_eq!(known_host_name("192.168.1.2", 2222), "[192.168.1.2]:2222");
+    assert_eq!(format_fingerprint(&[0; 32]), format!("SHA256:{}", "A".repeat(43)));
+}
+
+/// Checks the host key of sess (after handshake) against the known hosts in path.
+/// A key differing from the pinned one is a Trap::HostKey.
+pub fn check_host_key(sess: &Session, identifier: &str, port: u16, path: &Path) -> Result<HostKeyCheck, Trap> {
+    let (key, _) = sess.host_key()
+        .ok_or(Trap::HostKey(String::from("Host did not present a host key")))?;
+
+    let mut known_hosts = sess.known_hosts().map_err(|err| {
+        Trap::HostKey(format!("Could not init known hosts: {}", err))
+    })?;
+
+    if path.exists() {
+        known_hosts.read_file(path, KnownHostFileKind::OpenSSH).map_err(|err| {
+            Trap::HostKey(format!("Could not read known hosts {:?}: {}", path, err))
+        })?;
+    }
+
+    match known_hosts.check_port(identifier, port, key) {
+        CheckResult::Match => Ok(HostKeyCheck::Match),
+        CheckResult::NotFound => Ok(HostKeyCheck::NotFound),
+        CheckResult::Mismatch => Err(Trap::HostKey(format!(
+            "Host key of {} ({}) does not match the one pinned in {:?}. The host may be spoofed!",
+            known_host_name(identifier, port),
+            fingerprint(sess).unwrap_or_default(),
+            path
+        ))),
+        CheckResult::Failure => Err(Trap::HostKey(format!("Could not check host key of {}", identifier))),
+    }
+}
+
+/// Pins the host key of sess in the known hosts at path (trust on first use).
+/// The key is appended, leaving the rest of the file as it is.
+pub fn add_host_key(sess: &Session, identifier: &str, port: u16, path: &Path) -> Result<(), Trap> {
+    let (key, key_type) = sess.host_key()
+        .ok_or(Trap::HostKey(String::from("Host did not present a host key")))?;
+
+    let mut known_hosts = sess.known_hosts().map_err(|err| {
+        Trap::HostKey(format!("Could not init known hosts: {}", err))
+    })?;
+
+    let name = known_host_name(identifier, port);
+    known_hosts.add(&name, key, "added by rensen", key_type.into()).map_err(|err| {
+        Trap::HostKey(format!("Could not add host key of {}: {}", name, err))
+    })?;
+
+    let mut line = String::new();
+    for host in known_hosts.iter() {
+        let host = host.map_err(|err| Trap::HostKey(format!("Could not read known host: {}", err)))?;
+        line = known_hosts.write_string(&host, KnownHostFileKind::OpenSSH).map_err(|err| {
+            Trap::HostKey(format!("Could not format host key of {}: {}", name, err))
+        })?;
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| {
+            Trap::FS(format!("Could not create directory {:?}: {}", parent, err))
+        })?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| Trap::FS(format!("Could not open known hosts {:?}: {}", path, err)))?;
+
+    writeln!(file, "{}", line.trim_end()).map_err(|err| {
+        Trap::FS(format!("Could not write known hosts {:?}: {}", path, err))
+    })?;
+
+    Ok(())
+}
@@ -6,3 +6,13 @@ pub mod utils;
 pub mod compiler;
 pub mod snapshot;
 pub mod traits;
+pub mod filter;
+pub mod known_hosts;
+pub mod tunnel;
+pub mod archive;
+pub mod crypto;
+pub mod chunks;
+pub mod delta;
+pub mod prune;
+pub mod synthesize;
+pub mod restore;
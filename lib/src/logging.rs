@@ -26,6 +26,9 @@ pub enum Trap {
     Serialize(String),
     Metadata(String),
     Scheduler(String),
+    HostKey(String),
+    Hook(String),
+    Crypto(String),
 
 
 }
@@ -49,8 +52,18 @@ pub fn log_trap(global_config: &GlobalConfig, trap: &Trap) {
         Trap::Deserialize(msg)  => format!("Deserialize: {}", msg),
         Trap::Metadata(msg)     => format!("Metadata: {}", msg),
         Trap::Scheduler(msg)     => format!("Scheduler: {}", msg),
+        Trap::HostKey(msg)      => format!("HostKey: {}", msg),
+        Trap::Hook(msg)         => format!("Hook: {}", msg),
+        Trap::Crypto(msg)       => format!("Crypto: {}", msg),
     };
-    
+
+    log_message(global_config, &trap_msg);
+    error!("{}", trap_msg);
+}
+
+/// Writes msg to the backup log, also used for output
+/// which is not an error (like the output of hooks)
+pub fn log_message(global_config: &GlobalConfig, msg: &str) {
     // Opening log file
     if !Path::new(&global_config.log).exists() {
         let _ = File::create(&global_config.log);
@@ -69,9 +82,7 @@ pub fn log_trap(global_config: &GlobalConfig, trap: &Trap) {
 
     let current_time = get_datetime();
 
-    if let Err(err) = writeln!(file, "[{}] {}", current_time, trap_msg) {
+    if let Err(err) = writeln!(file, "[{}] {}", current_time, msg) {
         eprintln!("Problems writing to log file `{:?}`. Please check permissions: {}", &global_config.log, err);
     }
-
-    error!("{}", trap_msg);
 }
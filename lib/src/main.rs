@@ -8,6 +8,16 @@ pub mod config;
 pub mod tests;
 pub mod traits;
 pub mod snapshot;
+pub mod filter;
+pub mod known_hosts;
+pub mod tunnel;
+pub mod archive;
+pub mod crypto;
+pub mod chunks;
+pub mod delta;
+pub mod prune;
+pub mod synthesize;
+pub mod restore;
 pub use traits::{Rsync, JsonFile, YamlFile};
 
 
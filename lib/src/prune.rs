@@ -0,0 +1,289 @@
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{Datelike, Duration, Local, NaiveDateTime};
+
+use crate::logging::{Trap, log_trap};
+use crate::config::{GlobalConfig, HostConfig, Retention};
+use crate::traits::JsonFile;
+use crate::record::Record;
+use crate::chunks::{ChunkStore, chunk_root};
+use crate::utils::lock_host;
+
+/// Format of the snapshot names (see `utils::get_datetime`)
+const DATETIME_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+
+/// Key of the period (day, week, ..) a snapshot is in
+type Period = fn(&NaiveDateTime) -> (i32, u32);
+
+/// What a prune of a host did
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub kept: Vec<String>,   // snapshots, oldest first
+    pub pruned: Vec<String>, // snapshots, oldest first
+    pub archives: usize,     // archives deleted
+    pub chunks: usize,       // chunks deleted
+    pub freed: u64,          // bytes
+}
+
+/// Parses the datetime of a snapshot name, `None` if it is not one
+pub fn snapshot_datetime(name: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(name, DATETIME_FORMAT).ok()
+}
+
+/// Decides which snapshots the retention keeps. Datetimes are sorted newest first,
+/// the returned flags are in the same order.
+///
+/// Every keep rule goes through the snapshots from the newest, keeping the first one
+/// of every period (day, week, ..) until it has kept as many as it is set to.
+pub fn select_kept(datetimes: &[NaiveDateTime], retention: &Retention, now: NaiveDateTime) -> Vec<bool> {
+    let mut kept = vec![!retention.has_keep_rules(); datetimes.len()];
+
+    if let Some(keep_last) = retention.keep_last {
+        kept.iter_mut().take(keep_last).for_each(|kept| *kept = true);
+    }
+
+    let periods: [(Option<usize>, Period); 4] = [
+        (retention.keep_daily,   |datetime| (datetime.year(), datetime.ordinal())),
+        (retention.keep_weekly,  |datetime| (datetime.iso_week().year(), datetime.iso_week().week())),
+        (retention.keep_monthly, |datetime| (datetime.year(), datetime.month())),
+        (retention.keep_yearly,  |datetime| (datetime.year(), 0)),
+    ];
+
+    for (keep, period) in periods {
+        let mut remaining = keep.unwrap_or(0);
+        let mut last_period = None;
+
+        for (i, datetime) in datetimes.iter().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+
+            if last_period != Some(period(datetime)) {
+                last_period = Some(period(datetime));
+                kept[i] = true;
+                remaining -= 1;
+            }
+        }
+    }
+
+    if let Some(max_age) = retention.max_age {
+        let oldest = now - Duration::days(max_age as i64);
+        for (i, datetime) in datetimes.iter().enumerate() {
+            if *datetime < oldest {
+                kept[i] = false;
+            }
+        }
+    }
+
+    // Never pruning every snapshot of a host
+    if let Some(latest) = kept.first_mut() {
+        *latest = true;
+    }
+
+    kept
+}
+
+/// Applies the retention of the host to its snapshots.
+///
+/// The records of the pruned snapshots are deleted. As the entries of a record point
+/// into the archives of earlier snapshots (FileEntry.snapshot_path), an archive is only
+/// deleted once no live record (the kept ones and record.json) references it anymore.
+/// Chunks of deduplicated snapshots lose the references of the pruned records, and the
+/// ones left unreferenced are garbage collected.
+///
+/// Takes the lock of the host (see `lock_host`), waiting for a backup or synthesis of it to finish.
+pub fn prune(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<PruneReport, Trap> {
+    let host_root_path = global_config.backups.join(&host_config.identifier);
+    if !host_root_path.exists() {
+        return Ok(PruneReport::default());
+    }
+
+    let _host_lock = lock_host(&host_root_path)?;
+    prune_locked(global_config, host_config)
+}
+
+/// `prune` for backups and syntheses, which hold the lock of the host already
+pub(crate) fn prune_locked(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<PruneReport, Trap> {
+    let mut report = PruneReport::default();
+    let retention = match &host_config.retention {
+        Some(retention) => retention,
+        None => return Ok(report),
+    };
+
+    // $HOME/destination/$identifier
+    let host_root_path = global_config.backups.join(&host_config.identifier);
+    let record_dir_path = host_root_path.join(".records");
+
+    let mut snapshots = dated_records(&record_dir_path)?;
+    snapshots.sort_by_key(|snapshot| Reverse(snapshot.0));
+
+    let datetimes: Vec<NaiveDateTime> = snapshots.iter().map(|snapshot| snapshot.0).collect();
+    let kept = select_kept(&datetimes, retention, Local::now().naive_local());
+
+    // Every archive a live record has entries in
+    let mut live_records = vec![record_dir_path.join("record.json")];
+    live_records.extend(snapshots.iter().zip(&kept).filter(|(_, kept)| **kept).map(|(snapshot, _)| snapshot.1.clone()));
+
+    let mut referenced: BTreeSet<OsString> = BTreeSet::new();
+    for record_path in live_records.iter().filter(|path| path.exists()) {
+        let record = Record::deserialize_json(record_path).map_err(|err| {
+            Trap::Deserialize(format!("Could not deserialize record {:?}, not pruning: {}", record_path, err))
+        })?;
+
+        // Deleted files stay restorable from the archive their last entry is in
+        referenced.extend(record.snapshot.retained_entries()
+            .filter_map(|entry| entry.snapshot_path.file_name().map(|name| name.to_os_string())));
+    }
+
+    // Deleting the records of the pruned snapshots, releasing their chunks
+    let mut released: Vec<Vec<String>> = Vec::new();
+    for (snapshot, kept) in snapshots.iter().zip(&kept).rev() {
+        let name = snapshot.1.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        if *kept {
+            report.kept.push(name);
+            continue;
+        }
+
+        let record = match Record::deserialize_json(&snapshot.1) {
+            Ok(record) => record,
+            Err(err) => {
+                log_trap(global_config, &Trap::Deserialize(format!("Could not deserialize record {:?}, not pruning it: {}", snapshot.1, err)));
+                report.kept.push(name);
+                continue;
+            }
+        };
+
+        let chunks: Vec<String> = record.snapshot.retained_entries()
+            .filter_map(|entry| entry.chunks.clone())
+            .flatten()
+            .collect();
+
+        report.freed += fs::metadata(&snapshot.1).map(|metadata| metadata.len()).unwrap_or(0);
+        fs::remove_file(&snapshot.1).map_err(|err| {
+            Trap::FS(format!("Could not delete record {:?}: {}", snapshot.1, err))
+        })?;
+
+        released.push(chunks);
+        report.pruned.push(name);
+    }
+
+    // Archives after the latest record belong to a backup which is still running
+    let latest = datetimes.first().copied();
+    report.archives = remove_unreferenced_archives(&host_root_path, &referenced, latest, &mut report.freed)?;
+
+    let chunk_root = chunk_root(global_config);
+    if chunk_root.exists() {
+        let chunk_store = ChunkStore::open(&chunk_root)?;
+        // Every record referenced each of its chunks once
+        for chunks in released.iter().filter(|chunks| !chunks.is_empty()) {
+            chunk_store.remove_refs(chunks.iter())?;
+        }
+
+        let (chunks, freed) = chunk_store.gc()?;
+        report.chunks = chunks;
+        report.freed += freed;
+    }
+
+    Ok(report)
+}
+
+/// The dated records of a host (all but record.json) with the datetime of their snapshot
+pub fn dated_records(record_dir_path: &Path) -> Result<Vec<(NaiveDateTime, PathBuf)>, Trap> {
+    let entries = match fs::read_dir(record_dir_path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Trap::FS(format!("Could not read directory {:?}: {}", record_dir_path, err))),
+    };
+
+    Ok(entries.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .filter_map(|path| {
+            let datetime = snapshot_datetime(&path.file_stem()?.to_string_lossy())?;
+            Some((datetime, path))
+        })
+        .collect())
+}
+
+/// Deletes the archives in host_root_path (`<datetime>.tar.<codec>`, and their indexes) of snapshots no live record
+/// references, up to latest. Returns the number of archives deleted.
+fn remove_unreferenced_archives(host_root_path: &Path, referenced: &BTreeSet<OsString>, latest: Option<NaiveDateTime>, freed: &mut u64) -> Result<usize, Trap> {
+    let latest = match latest {
+        Some(latest) => latest,
+        None => return Ok(0),
+    };
+
+    let entries = fs::read_dir(host_root_path).map_err(|err| {
+        Trap::FS(format!("Could not read directory {:?}: {}", host_root_path, err))
+    })?;
+
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+
+        // Hidden files are records, keys and archives still being written
+        let snapshot = match file_name.split_once(".tar") {
+            Some((snapshot, _)) if !file_name.starts_with('.') => snapshot.to_string(),
+            _ => continue,
+        };
+
+        match snapshot_datetime(&snapshot) {
+            Some(datetime) if datetime <= latest => (),
+            _ => continue,
+        }
+
+        if referenced.contains(&OsString::from(&snapshot)) {
+            continue;
+        }
+
+        let size = entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        fs::remove_file(entry.path()).map_err(|err| {
+            Trap::FS(format!("Could not delete archive {:?}: {}", entry.path(), err))
+        })?;
+
+        // Member indexes go with their archive
+        *freed += size;
+        if !file_name.ends_with(".index") {
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[test]
+fn test_select_kept() {
+    let now = snapshot_datetime("2026-10-18-12-00-00").unwrap();
+
+    // Two snapshots a day, newest first
+    let datetimes: Vec<NaiveDateTime> = (0..120)
+        .map(|i| now - Duration::hours(12 * i))
+        .collect();
+
+    let kept = |retention: &Retention| -> Vec<usize> {
+        select_kept(&datetimes, retention, now).iter().enumerate()
+            .filter(|(_, kept)| **kept)
+            .map(|(i, _)| i)
+            .collect()
+    };
+
+    assert_eq!(kept(&Retention::default()).len(), datetimes.len());
+    assert_eq!(kept(&Retention { keep_last: Some(3), ..Default::default() }), vec![0, 1, 2]);
+    assert_eq!(kept(&Retention { keep_daily: Some(3), ..Default::default() }), vec![0, 2, 4]);
+
+    // 2026-10-18 is a sunday, the weeks start on the mondays before
+    assert_eq!(kept(&Retention { keep_weekly: Some(2), ..Default::default() }), vec![0, 14]);
+    assert_eq!(kept(&Retention { keep_monthly: Some(2), keep_last: Some(1), ..Default::default() }), vec![0, 36]);
+
+    // Max age prunes regardless of the keep rules, but never the latest
+    assert_eq!(kept(&Retention { max_age: Some(1), ..Default::default() }), vec![0, 1, 2]);
+    assert_eq!(kept(&Retention { keep_monthly: Some(12), max_age: Some(10), ..Default::default() }), vec![0]);
+    assert_eq!(select_kept(&datetimes[100..], &Retention { max_age: Some(1), ..Default::default() }, now), {
+        let mut kept = vec![false; 20];
+        kept[0] = true;
+        kept
+    });
+}
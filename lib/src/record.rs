@@ -5,15 +5,46 @@ use std::io::prelude::*;
 use crate::traits::JsonFile;
 use std::fmt::{Display, Formatter, Result};
 use crate::snapshot::*;
+use crate::archive::Codec;
+use crate::crypto::{self, Encryptor, decrypting_reader};
 
 
 /* listened to "Plastic Love" while coding this. */
 
+/// The kind of backup which produced a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupKind {
+    Full,         // every file copied from the host
+    Incremental,  // files changed since the previous snapshot
+    Differential, // files changed since the last full backup
+    Synthetic,    // full backup consolidated from earlier snapshots
+}
+
+impl Display for BackupKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            BackupKind::Full         => write!(f, "full"),
+            BackupKind::Incremental  => write!(f, "incremental"),
+            BackupKind::Differential => write!(f, "differential"),
+            BackupKind::Synthetic    => write!(f, "synthetic"),
+        }
+    }
+}
+
 /// A record storing the data for precompressed files.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Record {
     pub size: u64,
     pub snapshot: Snapshot,
+    #[serde(default)]
+    pub codec: Codec, // codec of the latest snapshot's archive
+    #[serde(default)]
+    pub kind: Option<BackupKind>, // kind of the latest snapshot (unknown before it was recorded)
+    #[serde(default)]
+    pub incrementals: u32, // incremental backups since the last full one
+    #[serde(default)]
+    pub last_full: Option<String>, // the last full (or synthetic) snapshot, which differential backups are taken from
 }
 
 impl Record {
@@ -21,8 +52,17 @@ impl Record {
         Record {
             size: 0,
             snapshot: Snapshot::new(),
+            codec: Codec::default(),
+            kind: None,
+            incrementals: 0,
+            last_full: None,
         }
     }
+
+    /// If a full backup is due, by the host's rule of one every full_every incrementals
+    pub fn is_full_due(&self, full_every: Option<u32>) -> bool {
+        full_every.is_some_and(|full_every| self.incrementals >= full_every)
+    }
 }
 
 impl Display for Record {
@@ -31,17 +71,27 @@ impl Display for Record {
     }
 }
 
+/// Records are encrypted like the archives when the repository key is installed
 impl JsonFile for Record {
 
     fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
         let mut file = File::create(file_path)?;
         let json_str = serde_json::to_string_pretty(&self)?;
-        write!(file, "{}", json_str)?;
+
+        match crypto::installed() {
+            Some(keys) => {
+                let mut encryptor = Encryptor::new(file, &keys)?;
+                write!(encryptor, "{}", json_str)?;
+                encryptor.finish()?;
+            },
+            None => write!(file, "{}", json_str)?,
+        }
+
         Ok(())
     }
 
     fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
-        let mut file = match File::open(file_path) {
+        let file = match File::open(file_path) {
             Ok(v) => v,
             Err(_) => {
                 return Ok(Record::new());
@@ -49,7 +99,7 @@ impl JsonFile for Record {
         };
 
         let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
+        decrypting_reader(file)?.read_to_string(&mut contents)?;
         let record: Record = serde_json::from_str(&contents)?;
         Ok(record)
     }
@@ -74,3 +124,19 @@ fn test_serialize_record() {
 fn test_deserialize_record() {
     let record: Record = Record::deserialize_json(Path::new("tests/record.json")).unwrap();
 }
+
+#[test]
+fn test_is_full_due() {
+    let mut record = Record::new();
+    assert!(!record.is_full_due(None));
+    assert!(!record.is_full_due(Some(6)));
+
+    record.incrementals = 6;
+    assert!(record.is_full_due(Some(6)));
+    assert!(!record.is_full_due(None));
+
+    // Records from before the kind was recorded
+    let record: Record = serde_json::from_str(r#"{"size": 0, "snapshot": {"entries": {}, "deleted_entries": []}}"#).unwrap();
+    assert_eq!(record.kind, None);
+    assert_eq!(record.incrementals, 0);
+}
@@ -0,0 +1,527 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime};
+use fxhash::{FxHashMap, FxHashSet};
+use ssh2::{FileStat, RenameFlags};
+
+use crate::logging::Trap;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::traits::{JsonFile, Rsync};
+use crate::record::Record;
+use crate::snapshot::{FileEntry, EntryKind};
+use crate::backup::rsync::Sftp;
+use crate::archive::{Codec, open_archive, copy_member};
+use crate::chunks::{ChunkStore, chunk_root};
+use crate::prune::dated_records;
+use crate::utils::{source_subtree, shell_quote};
+
+/// What is done with a file which exists at its destination already
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    Overwrite,
+    #[default]
+    Skip,
+    Rename, // restored next to it, as `<name>.restored-<snapshot>`
+}
+
+/// What is to be restored, and where
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    pub snapshot: String,             // snapshot or point in time, see `resolve_snapshot`
+    pub paths: Vec<PathBuf>,          // source paths (and everything under them), all if empty
+    pub target_path: Option<PathBuf>, // remote directory the paths are restored under, instead of in place
+    pub conflict: ConflictPolicy,
+    pub dry_run: bool,                // only plan, writing nothing
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestoreAction {
+    Create,
+    Overwrite,
+    Skip,
+    Rename,
+}
+
+/// An entry of the snapshot, with where and how it is restored
+#[derive(Debug, Clone)]
+pub struct RestoreItem {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub action: RestoreAction,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub snapshot: String, // the snapshot restored from
+    pub items: Vec<RestoreItem>,
+    pub failed: Vec<(PathBuf, Trap)>, // destination, error
+}
+
+/// member: (entry, destination), of the files restored from an archive
+type Members<'a> = FxHashMap<PathBuf, (&'a FileEntry, &'a Path)>;
+
+/// The record of a snapshot of the host, `latest` (or `record`) being record.json
+pub fn record_path(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str) -> PathBuf {
+    let snapshot = match snapshot.trim() {
+        "latest" => "record",
+        snapshot => snapshot,
+    };
+
+    global_config.backups
+        .join(&host_config.identifier)
+        .join(".records")
+        .join(format!("{}.json", snapshot))
+}
+
+/// Resolves a snapshot of the host by name, or by a point in time (see `parse_point_in_time`)
+/// to the latest snapshot taken at or before it. `latest` (or nothing) is the latest snapshot.
+///
+/// The snapshot has the files as they were at the time, including the ones deleted since.
+pub fn resolve_snapshot(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str) -> Result<String, Trap> {
+    let snapshot = snapshot.trim();
+    if snapshot.is_empty() || snapshot == "latest" || snapshot == "record" {
+        return Ok(String::from("record"));
+    }
+
+    if record_path(global_config, host_config, snapshot).exists() {
+        return Ok(snapshot.to_string());
+    }
+
+    let point = parse_point_in_time(snapshot, Local::now().naive_local())
+        .ok_or(Trap::InvalidInput(format!("`{}` is neither a snapshot nor a point in time", snapshot)))?;
+
+    let record_dir_path = global_config.backups.join(&host_config.identifier).join(".records");
+    dated_records(&record_dir_path)?.into_iter()
+        .filter(|(datetime, _)| *datetime <= point)
+        .max_by_key(|(datetime, _)| *datetime)
+        .and_then(|(_, path)| Some(path.file_stem()?.to_string_lossy().into_owned()))
+        .ok_or(Trap::Missing(format!("No snapshot of `{}` at or before {}", host_config.identifier, point)))
+}
+
+/// Parses a point in time, relative to now: a time (`2026-10-01 14:00`, `2026-10-01T14:00:00`),
+/// a date (`2026-10-01`, the end of the day), a unix timestamp, or a time ago (`3 days ago`,
+/// `2 weeks 3 days ago`, `12h ago`). Months are 30 days and years 365.
+pub fn parse_point_in_time(input: &str, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let input = input.trim().to_lowercase();
+
+    if input == "now" {
+        return Some(now);
+    }
+
+    if let Some(ago) = input.strip_suffix("ago") {
+        return parse_duration(ago).map(|duration| now - duration);
+    }
+
+    if !input.is_empty() && input.chars().all(|c| c.is_ascii_digit()) {
+        let timestamp = input.parse().ok()?;
+        return DateTime::from_timestamp(timestamp, 0).map(|datetime| datetime.with_timezone(&Local).naive_local());
+    }
+
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dt%H:%M:%S", "%Y-%m-%dt%H:%M", "%Y-%m-%d-%H-%M-%S"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(&input, format) {
+            return Some(datetime);
+        }
+    }
+
+    NaiveDate::parse_from_str(&input, "%Y-%m-%d").ok()?.and_hms_opt(23, 59, 59)
+}
+
+/// Parses a duration of amounts with units, like `2 weeks 3 days` or `12h`
+fn parse_duration(input: &str) -> Option<Duration> {
+    let mut duration = Duration::zero();
+    let mut amount: Option<i64> = None;
+
+    // Splitting `12h` into `12` and `h`
+    let mut tokens = Vec::new();
+    for word in input.split_whitespace() {
+        match word.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) if i > 0 => tokens.extend([&word[..i], &word[i..]]),
+            _ => tokens.push(word),
+        }
+    }
+
+    for token in tokens {
+        if let Ok(n) = token.parse() {
+            if amount.replace(n).is_some() {
+                return None;
+            }
+            continue;
+        }
+
+        let n = amount.take()?;
+        duration += match token.trim_end_matches(',') {
+            "s" | "sec" | "secs" | "second" | "seconds" => Duration::try_seconds(n)?,
+            "m" | "min" | "mins" | "minute" | "minutes" => Duration::try_minutes(n)?,
+            "h" | "hour" | "hours" => Duration::try_hours(n)?,
+            "d" | "day" | "days" => Duration::try_days(n)?,
+            "w" | "week" | "weeks" => Duration::try_weeks(n)?,
+            "month" | "months" => Duration::try_days(n.checked_mul(30)?)?,
+            "y" | "year" | "years" => Duration::try_days(n.checked_mul(365)?)?,
+            _ => return None,
+        };
+    }
+
+    match (amount, duration.is_zero()) {
+        (None, false) => Some(duration),
+        _ => None,
+    }
+}
+
+/// If source is one of paths or under one of them, every source being selected without paths
+pub fn is_selected(source: &Path, paths: &[PathBuf]) -> bool {
+    paths.is_empty() || paths.iter().any(|path| source.starts_with(path))
+}
+
+/// Restores a snapshot of host_config to target over SFTP (the host itself, or another one).
+///
+/// Files are streamed from the archives (or the chunk store) of the snapshot straight to a
+/// temporary file next to their destination, which is moved into place once its permissions
+/// and times are set. Ownership is only restored to the host itself, as the ids may differ
+/// on other hosts. Links and special files are made with `ln`, `mkfifo` and `mknod`.
+///
+/// A file failing to restore does not stop the others, nor does an archive which cannot be read
+/// (its files fail), the failures are in the report.
+pub fn restore(global_config: &GlobalConfig, host_config: &HostConfig, target: &HostConfig, options: &RestoreOptions) -> Result<RestoreReport, Trap> {
+    let snapshot = resolve_snapshot(global_config, host_config, &options.snapshot)?;
+    let record_path = record_path(global_config, host_config, &snapshot);
+    if !record_path.exists() {
+        return Err(Trap::Missing(format!("No snapshot `{}` of `{}`", options.snapshot, host_config.identifier)));
+    }
+
+    let record = Record::deserialize_json(&record_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not deserialize record {:?}: {}", record_path, err)))?;
+
+    let mut sftp = Sftp::new(target, global_config, Record::new(), false);
+    sftp.connect()?;
+    sftp.auth()?;
+
+    let snapshot_name = record_path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let same_host = target.identifier == host_config.identifier;
+
+    let session = sftp.session()?;
+    let remote = session.sftp().map_err(|err| {
+        Trap::Session(format!("Could not init SFTP session: {}", err))
+    })?;
+
+    // Planning every entry, files first so that hard links can point at them
+    let mut selected: Vec<(&PathBuf, &FileEntry)> = record.snapshot.entries.iter()
+        .filter(|(source, _)| is_selected(source, &options.paths))
+        .collect();
+    selected.sort_by_key(|(source, entry)| (!entry.is_file(), *source));
+
+    if selected.is_empty() {
+        return Err(Trap::Missing(format!("Nothing in snapshot `{}` matches {:?}", options.snapshot, options.paths)));
+    }
+
+    let mut report = RestoreReport { snapshot: snapshot_name.clone(), ..Default::default() };
+    for (source, _) in &selected {
+        let destination = match &options.target_path {
+            Some(target_path) => source_subtree(target_path, source),
+            None => source.to_path_buf(),
+        };
+
+        let (destination, action) = match remote.lstat(&destination) {
+            Err(_) => (destination, RestoreAction::Create),
+            Ok(_) => match options.conflict {
+                ConflictPolicy::Overwrite => (destination, RestoreAction::Overwrite),
+                ConflictPolicy::Skip => (destination, RestoreAction::Skip),
+                ConflictPolicy::Rename => (free_path(&remote, &destination, &snapshot_name), RestoreAction::Rename),
+            },
+        };
+
+        report.items.push(RestoreItem { source: source.to_path_buf(), destination, action });
+    }
+
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    // source: destination, of everything which is restored
+    let destinations: FxHashMap<&Path, &Path> = report.items.iter()
+        .filter(|item| item.action != RestoreAction::Skip)
+        .map(|item| (item.source.as_path(), item.destination.as_path()))
+        .collect();
+
+    let mut created_dirs: FxHashSet<PathBuf> = FxHashSet::default();
+    for destination in destinations.values() {
+        if let Some(parent) = destination.parent() {
+            create_remote_dirs(&remote, parent, &mut created_dirs);
+        }
+    }
+
+    let mut failed: Vec<(PathBuf, Trap)> = Vec::new();
+
+    // (snapshot, codec): members, of the files in archives
+    let mut archives: FxHashMap<(PathBuf, Codec), Members> = FxHashMap::default();
+    let mut chunk_store: Option<ChunkStore> = None;
+
+    for (source, entry) in selected.iter().filter(|(_, entry)| entry.is_file()) {
+        let destination = match destinations.get(source.as_path()) {
+            Some(destination) => *destination,
+            None => continue,
+        };
+
+        let written = match &entry.chunks {
+            Some(chunks) => {
+                if chunk_store.is_none() {
+                    chunk_store = Some(ChunkStore::open(&chunk_root(global_config))?);
+                }
+                write_remote_file(&remote, destination, entry, chunk_store.as_ref().unwrap().reader(chunks), same_host)
+            },
+
+            // Snapshots from before the archives were streamed are directories
+            None if entry.file_path.exists() => fs::File::open(&entry.file_path)
+                .map_err(|err| Trap::FS(format!("Could not open {:?}: {}", entry.file_path, err)))
+                .and_then(|file| write_remote_file(&remote, destination, entry, file, same_host)),
+
+            None => {
+                match entry.file_path.strip_prefix(&entry.snapshot_path) {
+                    Ok(member) => {
+                        archives.entry((entry.snapshot_path.clone(), entry.codec)).or_default()
+                            .insert(member.to_path_buf(), (entry, destination));
+                    },
+                    Err(err) => failed.push((destination.to_path_buf(), Trap::Missing(format!("{:?} is not within its snapshot: {}", entry.file_path, err)))),
+                }
+                continue;
+            },
+        };
+
+        if let Err(err) = written {
+            failed.push((destination.to_path_buf(), err));
+        }
+    }
+
+    for ((snapshot_path, codec), mut members) in archives {
+        let archive_path = crate::archive::archive_path(&snapshot_path, codec);
+        let mut found: Members = FxHashMap::default();
+
+        let read = (|| -> io::Result<()> {
+            let mut archive = open_archive(&archive_path, codec)?;
+            for member in archive.entries()? {
+                let member = member?;
+                let member_path = member.path()?.to_path_buf();
+
+                // A member appended again (when reading it failed at first) is restored again, the last one wins
+                let restored = members.remove(&member_path).or_else(|| found.remove(&member_path));
+                if let Some((entry, destination)) = restored {
+                    if let Err(err) = write_remote_file(&remote, destination, entry, member, same_host) {
+                        failed.push((destination.to_path_buf(), err));
+                    }
+                    found.insert(member_path, (entry, destination));
+                }
+            }
+
+            Ok(())
+        })();
+
+        // An archive which cannot be read fails the files not restored from it yet, the others are restored on
+        for (_, (_, destination)) in members {
+            let err = match &read {
+                Ok(()) => Trap::Missing(format!("Not found in archive {:?}", archive_path)),
+                Err(err) => Trap::FS(format!("Could not read archive {:?}: {}", archive_path, err)),
+            };
+            failed.push((destination.to_path_buf(), err));
+        }
+    }
+
+    // Links and special files
+    for (source, entry) in selected.iter().filter(|(_, entry)| !entry.is_file()) {
+        let destination = match destinations.get(source.as_path()) {
+            Some(destination) => *destination,
+            None => continue,
+        };
+
+        if let Err(err) = make_special_file(&sftp, &session, destination, entry, &destinations) {
+            failed.push((destination.to_path_buf(), err));
+        }
+    }
+
+    report.failed = failed;
+    Ok(report)
+}
+
+/// Streams the file source of a snapshot of host_config to out, returning its size.
+///
+/// Only the file is read: from the chunk store, or as the one member of the archive it is
+/// in, found by the member index of the archive (see `copy_member`).
+/// A file deleted before the snapshot is restored as it was last backed up, as long as
+/// the snapshot it is in has not been pruned.
+pub fn restore_file<W: Write>(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str, source: &Path, out: &mut W) -> Result<u64, Trap> {
+    let snapshot = &resolve_snapshot(global_config, host_config, snapshot)?;
+    let record_path = record_path(global_config, host_config, snapshot);
+    if !record_path.exists() {
+        return Err(Trap::Missing(format!("No snapshot `{}` of `{}`", snapshot, host_config.identifier)));
+    }
+
+    let record = Record::deserialize_json(&record_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not deserialize record {:?}: {}", record_path, err)))?;
+
+    // Files deleted by the time of the snapshot, as they were last backed up.
+    // Deletions recorded before their entries were kept have only a path.
+    let entry = record.snapshot.entries.get(source).or_else(|| {
+        record.snapshot.deleted_entries.get(source)
+            .filter(|deleted| deleted.deleted_in.is_some())
+            .map(|deleted| &deleted.entry)
+    });
+
+    let entry = match entry {
+        Some(entry) if entry.is_file() => entry,
+        Some(_) => return Err(Trap::InvalidInput(format!("{:?} is not a regular file in snapshot `{}`", source, snapshot))),
+        None => return Err(Trap::Missing(format!("{:?} is not in snapshot `{}`", source, snapshot))),
+    };
+
+    let copy_err = |err: io::Error| Trap::Copy(format!("Could not restore {:?}: {}", source, err));
+
+    if let Some(chunks) = &entry.chunks {
+        let chunk_store = ChunkStore::open(&chunk_root(global_config))?;
+        return io::copy(&mut chunk_store.reader(chunks), out).map_err(copy_err);
+    }
+
+    // Snapshots from before the archives were streamed are directories
+    if entry.file_path.exists() {
+        let mut file = fs::File::open(&entry.file_path).map_err(copy_err)?;
+        return io::copy(&mut file, out).map_err(copy_err);
+    }
+
+    let member = entry.file_path.strip_prefix(&entry.snapshot_path)
+        .map_err(|err| Trap::Missing(format!("{:?} is not within its snapshot: {}", entry.file_path, err)))?;
+
+    let archive_path = entry.archive_path();
+    copy_member(&archive_path, entry.codec, member, out).map_err(copy_err)?
+        .ok_or(Trap::Missing(format!("{:?} was not found in archive {:?}", source, archive_path)))
+}
+
+/// A path next to destination which does not exist yet, for restoring without overwriting
+fn free_path(remote: &ssh2::Sftp, destination: &Path, snapshot_name: &str) -> PathBuf {
+    let mut path = PathBuf::from(format!("{}.restored-{}", destination.display(), snapshot_name));
+
+    let mut i = 1;
+    while remote.lstat(&path).is_ok() {
+        path = PathBuf::from(format!("{}.restored-{}.{}", destination.display(), snapshot_name, i));
+        i += 1;
+    }
+
+    path
+}
+
+/// Creates dir and its missing parents on the remote host
+fn create_remote_dirs(remote: &ssh2::Sftp, dir: &Path, created: &mut FxHashSet<PathBuf>) {
+    if dir.as_os_str().is_empty() || created.contains(dir) {
+        return;
+    }
+
+    if remote.stat(dir).is_err() {
+        if let Some(parent) = dir.parent() {
+            create_remote_dirs(remote, parent, created);
+        }
+        let _ = remote.mkdir(dir, 0o755);
+    }
+
+    created.insert(dir.to_path_buf());
+}
+
+/// Writes entry.size bytes of data to a temporary file next to destination, sets the metadata
+/// of entry on it and moves it into place. The owner is set when set_owner (and permitted).
+fn write_remote_file<R: Read>(remote: &ssh2::Sftp, destination: &Path, entry: &FileEntry, data: R, set_owner: bool) -> Result<(), Trap> {
+    let file_name = destination.file_name()
+        .ok_or(Trap::InvalidInput(format!("Invalid destination {:?}", destination)))?;
+    let temp_path = destination.with_file_name(format!(".{}.rensen-restore", file_name.to_string_lossy()));
+
+    let written = (|| {
+        let mut file = remote.create(&temp_path).map_err(|err| {
+            Trap::Copy(format!("Could not create {:?}: {}", temp_path, err))
+        })?;
+
+        io::copy(&mut data.take(entry.size), &mut file).map_err(|err| {
+            Trap::Copy(format!("Could not write {:?}: {}", temp_path, err))
+        })?;
+        drop(file);
+
+        // Ownership before permissions, as chown clears setuid/setgid.
+        // Only succeeds when the user is root.
+        if let (true, Some(uid), Some(gid)) = (set_owner, entry.uid, entry.gid) {
+            let _ = remote.setstat(&temp_path, FileStat { size: None, uid: Some(uid), gid: Some(gid), perm: None, atime: None, mtime: None });
+        }
+
+        let mode = if entry.mode == 0 { 0o644 } else { entry.mode & 0o7777 };
+        remote.setstat(&temp_path, FileStat { size: None, uid: None, gid: None, perm: Some(mode), atime: Some(entry.mtime), mtime: Some(entry.mtime) })
+            .map_err(|err| Trap::Metadata(format!("Could not set metadata of {:?}: {}", temp_path, err)))?;
+
+        // Servers without the overwrite extension of rename refuse to replace the file
+        let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+        if remote.rename(&temp_path, destination, flags).is_err() {
+            let _ = remote.unlink(destination);
+            remote.rename(&temp_path, destination, flags).map_err(|err| {
+                Trap::Copy(format!("Could not move {:?} into place: {}", destination, err))
+            })?;
+        }
+
+        Ok(())
+    })();
+
+    if written.is_err() {
+        let _ = remote.unlink(&temp_path);
+    }
+
+    written
+}
+
+/// Makes a link or special file at destination with `ln`, `mkfifo` or `mknod` on the remote host.
+/// Hard links point at the destination of the file they link to, if it is restored.
+fn make_special_file(sftp: &Sftp, session: &ssh2::Session, destination: &Path, entry: &FileEntry, destinations: &FxHashMap<&Path, &Path>) -> Result<(), Trap> {
+    let mode = format!("{:o}", entry.mode & 0o7777);
+    let target = entry.link_target.as_ref();
+
+    let command = match (entry.kind, target) {
+        (EntryKind::Symlink, Some(target)) => format!("ln -sfn -- {} {}", shell_quote(target), shell_quote(destination)),
+        (EntryKind::HardLink, Some(target)) => {
+            let target = destinations.get(target.as_path())
+                .ok_or(Trap::Missing(format!("{:?} links to {:?}, which is not restored", destination, target)))?;
+            format!("ln -f -- {} {}", shell_quote(target), shell_quote(destination))
+        },
+        (EntryKind::Fifo, _) => format!("rm -f -- {1} && mkfifo -m {0} -- {1}", mode, shell_quote(destination)),
+        (EntryKind::CharDevice | EntryKind::BlockDevice, _) => {
+            let (major, minor) = entry.device
+                .ok_or(Trap::Missing(format!("No device numbers recorded for {:?}", destination)))?;
+            let kind = if entry.kind == EntryKind::CharDevice { "c" } else { "b" };
+            format!("rm -f -- {1} && mknod -m {0} -- {1} {2} {3} {4}", mode, shell_quote(destination), kind, major, minor)
+        },
+        _ => return Err(Trap::Missing(format!("Nothing recorded to restore {:?} from", destination))),
+    };
+
+    sftp.remote_exec(session, &command).map(|_| ())
+}
+
+#[test]
+fn test_parse_point_in_time() {
+    let now = NaiveDateTime::parse_from_str("2026-10-18 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    let at = |datetime: &str| NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S").ok();
+
+    assert_eq!(parse_point_in_time("3 days ago", now), at("2026-10-15 12:00:00"));
+    assert_eq!(parse_point_in_time("1 week 2 hours ago", now), at("2026-10-11 10:00:00"));
+    assert_eq!(parse_point_in_time("12h ago", now), at("2026-10-18 00:00:00"));
+    assert_eq!(parse_point_in_time("2026-10-01 14:00", now), at("2026-10-01 14:00:00"));
+    assert_eq!(parse_point_in_time("2026-10-01T14:00:30", now), at("2026-10-01 14:00:30"));
+    assert_eq!(parse_point_in_time("2026-10-01", now), at("2026-10-01 23:59:59"));
+    assert_eq!(parse_point_in_time("2026-10-01-14-00-00", now), at("2026-10-01 14:00:00"));
+
+    assert_eq!(parse_point_in_time("ago", now), None);
+    assert_eq!(parse_point_in_time("3 ago", now), None);
+    assert_eq!(parse_point_in_time("3 fortnights ago", now), None);
+    assert_eq!(parse_point_in_time("last tuesday", now), None);
+}
+
+#[test]
+fn test_is_selected() {
+    let paths = vec![PathBuf::from("/etc/nginx"), PathBuf::from("/var/lib/app/db.sqlite")];
+
+    assert!(is_selected(Path::new("/etc/nginx"), &paths));
+    assert!(is_selected(Path::new("/etc/nginx/nginx.conf"), &paths));
+    assert!(is_selected(Path::new("/var/lib/app/db.sqlite"), &paths));
+    assert!(!is_selected(Path::new("/etc/nginx-old/nginx.conf"), &paths));
+    assert!(!is_selected(Path::new("/var/lib/app/db.sqlite-wal"), &paths));
+    assert!(is_selected(Path::new("/anything"), &[]));
+}
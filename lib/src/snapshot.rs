@@ -1,11 +1,27 @@
 use std::collections::{BTreeMap, BTreeSet};
-use std::path::PathBuf;
-use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize, Deserializer};
 use std::cmp::Ordering;
 use std::fmt::{Display, Result, Formatter};
 use fxhash::FxHashMap;
 use std::rc::Rc;
 use std::thread;
+use crate::config::ChangeDetection;
+use crate::archive::{Codec, archive_path};
+
+/// The kind of remote entry a FileEntry describes.
+/// Everything but regular files are stored as metadata only.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    #[default]
+    File,
+    Symlink,     // link_target: what the link points to
+    HardLink,    // link_target: source path of the file it shares inode with
+    Fifo,
+    CharDevice,  // device: (major, minor)
+    BlockDevice, // device: (major, minor)
+}
 
 /// Wrapper for PathBuf holding its mtime as u64
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,6 +30,38 @@ pub struct FileEntry {
     pub snapshot_path: PathBuf, // root path (no extension)
     pub mtime: u64,
     pub size: u64,
+    #[serde(default)]
+    pub ctime: u64,             // remote ctime
+    #[serde(default)]
+    pub inode: u64,             // remote inode
+    #[serde(default)]
+    pub checksum: Option<String>, // sha256 (hex) of the content
+    #[serde(default)]
+    pub kind: EntryKind,
+    #[serde(default)]
+    pub mode: u32,              // permission bits
+    #[serde(default)]
+    pub link_target: Option<PathBuf>,
+    #[serde(default)]
+    pub device: Option<(u32, u32)>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    #[serde(default)]
+    pub owner: Option<String>,  // user name of uid on the remote host
+    #[serde(default)]
+    pub group: Option<String>,  // group name of gid on the remote host
+    #[serde(default)]
+    pub xattrs: Option<BTreeMap<String, String>>, // name: value (hex)
+    #[serde(default)]
+    pub acl: Option<String>,    // extended access ACL entries, comma separated
+    #[serde(default)]
+    pub command: Option<String>, // command whose output this is, for command sources
+    #[serde(default)]
+    pub codec: Codec,           // codec of the archive the entry is in (gzip before it was recorded)
+    #[serde(default)]
+    pub chunks: Option<Vec<String>>, // hashes of the content in the chunk store, instead of an archive
 }
 
 impl FileEntry {
@@ -23,6 +71,22 @@ impl FileEntry {
             snapshot_path: PathBuf::new(),
             mtime: u64::MIN,
             size: u64::MIN,
+            ctime: u64::MIN,
+            inode: u64::MIN,
+            checksum: None,
+            kind: EntryKind::File,
+            mode: u32::MIN,
+            link_target: None,
+            device: None,
+            uid: None,
+            gid: None,
+            owner: None,
+            group: None,
+            xattrs: None,
+            acl: None,
+            command: None,
+            codec: Codec::Gzip,
+            chunks: None,
         }
     }
 
@@ -32,10 +96,116 @@ impl FileEntry {
             snapshot_path,
             mtime,
             size,
+            ctime: u64::MIN,
+            inode: u64::MIN,
+            checksum: None,
+            kind: EntryKind::File,
+            mode: u32::MIN,
+            link_target: None,
+            device: None,
+            uid: None,
+            gid: None,
+            owner: None,
+            group: None,
+            xattrs: None,
+            acl: None,
+            command: None,
+            codec: Codec::Gzip,
+            chunks: None,
+        }
+    }
+
+    /// Regular files are the only entries with content in the archives
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    /// The archive of the snapshot the entry is in
+    pub fn archive_path(&self) -> PathBuf {
+        archive_path(&self.snapshot_path, self.codec)
+    }
+
+    /// Compares self (the current remote state) against the recorded entry
+    /// according to the change detection strategy.
+    pub fn has_changed(&self, recorded: &FileEntry, strategy: ChangeDetection) -> bool {
+        match strategy {
+            ChangeDetection::Mtime => self.mtime > recorded.mtime,
+            ChangeDetection::MtimeSize => {
+                self.mtime != recorded.mtime || self.size != recorded.size
+            },
+            ChangeDetection::Inode => {
+                self.mtime != recorded.mtime || self.size != recorded.size
+                    || self.ctime != recorded.ctime || self.inode != recorded.inode
+            },
+            ChangeDetection::Hash => {
+                self.checksum.is_none() || self.checksum != recorded.checksum
+            },
         }
     }
 }
 
+#[test]
+fn test_has_changed() {
+    let mut recorded = FileEntry::from(PathBuf::from("/backups/file"), PathBuf::from("/backups"), 100, 10);
+    recorded.inode = 7;
+    recorded.ctime = 100;
+    recorded.checksum = Some(String::from("abc"));
+
+    // Restored with an older mtime
+    let mut remote = FileEntry::from(PathBuf::new(), PathBuf::new(), 50, 10);
+    remote.inode = 7;
+    remote.ctime = 100;
+    remote.checksum = Some(String::from("abc"));
+    assert!(!remote.has_changed(&recorded, ChangeDetection::Mtime));
+    assert!(remote.has_changed(&recorded, ChangeDetection::MtimeSize));
+
+    // Same-second edit, changing size
+    remote.mtime = 100;
+    remote.size = 11;
+    assert!(!remote.has_changed(&recorded, ChangeDetection::Mtime));
+    assert!(remote.has_changed(&recorded, ChangeDetection::MtimeSize));
+
+    // Replaced file with same mtime and size
+    remote.size = 10;
+    remote.inode = 8;
+    assert!(!remote.has_changed(&recorded, ChangeDetection::MtimeSize));
+    assert!(remote.has_changed(&recorded, ChangeDetection::Inode));
+
+    // Content
+    assert!(!remote.has_changed(&recorded, ChangeDetection::Hash));
+    remote.checksum = Some(String::from("abd"));
+    assert!(remote.has_changed(&recorded, ChangeDetection::Hash));
+}
+
+#[test]
+fn test_deleted_entries() {
+    let mut snapshot = Snapshot::new();
+    snapshot.entries.insert(PathBuf::from("/etc/app.conf"), FileEntry::from(PathBuf::from("/backups/1/etc/app.conf"), PathBuf::from("/backups/1"), 100, 10));
+
+    // Keeps the entry, and where the deletion was seen
+    snapshot.mark_as_deleted(Path::new("/etc/app.conf"), "2");
+    assert!(snapshot.entries.is_empty());
+    assert!(snapshot.is_deleted(Path::new("/etc/app.conf")));
+
+    let deleted = &snapshot.deleted_entries[Path::new("/etc/app.conf")];
+    assert_eq!(deleted.entry.size, 10);
+    assert_eq!(deleted.entry.snapshot_path, PathBuf::from("/backups/1"));
+    assert_eq!(deleted.deleted_in.as_deref(), Some("2"));
+
+    // Its archive is still retained
+    let retained: Vec<&PathBuf> = snapshot.retained_entries().map(|entry| &entry.snapshot_path).collect();
+    assert_eq!(retained, vec![&PathBuf::from("/backups/1")]);
+
+    snapshot.undelete(Path::new("/etc/app.conf"));
+    assert!(!snapshot.is_deleted(Path::new("/etc/app.conf")));
+
+    // Deletions recorded as path pairs
+    let snapshot: Snapshot = serde_json::from_str(r#"{"entries": {}, "deleted_entries": [{"source": "/etc/old", "destination": "/backups/1/etc/old"}]}"#).unwrap();
+    let deleted = &snapshot.deleted_entries[Path::new("/etc/old")];
+    assert_eq!(deleted.entry.file_path, PathBuf::from("/backups/1/etc/old"));
+    assert_eq!(deleted.deleted_in, None);
+}
+
 /// Containg two pairing (equal) paths
 /// the local path (destination) and it's equivelent remote path (source)
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
@@ -66,12 +236,42 @@ impl PartialOrd for PathPair {
     }
 }
 
+/// A file which was deleted from the host, with its entry as it was last backed up.
+/// The entry still points at the snapshot its content is in.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletedEntry {
+    pub entry: FileEntry,
+    pub deleted_in: Option<String>, // snapshot in which the deletion was observed, None if recorded before it was kept
+}
+
 /// Entries containing the mtime of files.
 /// Using the source path as key, we can get data.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Snapshot {
     pub entries: FxHashMap<PathBuf, FileEntry>,
-    pub deleted_entries: BTreeSet<PathPair>,
+    #[serde(deserialize_with = "deserialize_deleted_entries")]
+    pub deleted_entries: BTreeMap<PathBuf, DeletedEntry>,
+}
+
+/// Deletions used to be recorded as a list of path pairs, without their entry
+fn deserialize_deleted_entries<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<BTreeMap<PathBuf, DeletedEntry>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DeletedEntries {
+        Entries(BTreeMap<PathBuf, DeletedEntry>),
+        Pairs(BTreeSet<PathPair>),
+    }
+
+    Ok(match DeletedEntries::deserialize(deserializer)? {
+        DeletedEntries::Entries(entries) => entries,
+        DeletedEntries::Pairs(pairs) => pairs.into_iter()
+            .map(|pair| {
+                let mut entry = FileEntry::new();
+                entry.file_path = pair.destination;
+                (pair.source, DeletedEntry { entry, deleted_in: None })
+            })
+            .collect(),
+    })
 }
 
 impl Display for Snapshot {
@@ -84,7 +284,7 @@ impl Snapshot {
     pub fn new() -> Self {
         Snapshot {
             entries: FxHashMap::default(),
-            deleted_entries: BTreeSet::new(),
+            deleted_entries: BTreeMap::new(),
         }
     }
 
@@ -95,17 +295,26 @@ impl Snapshot {
         );
     }
 
-    pub fn mark_as_deleted(&mut self, pair: PathPair) {
-        self.entries.remove(&pair.source);
-        self.deleted_entries.insert(pair);
+    /// Moves the entry of source to the deleted entries, deleted_in being the snapshot
+    /// in which it was found to be deleted
+    pub fn mark_as_deleted(&mut self, source: &Path, deleted_in: &str) {
+        if let Some(entry) = self.entries.remove(source) {
+            self.deleted_entries.insert(source.to_path_buf(), DeletedEntry { entry, deleted_in: Some(deleted_in.to_string()) });
+        }
+    }
+
+    pub fn is_deleted(&self, source: &Path) -> bool {
+        self.deleted_entries.contains_key(source)
     }
 
-    pub fn is_deleted(&self, pair: &PathPair) -> bool {
-        self.deleted_entries.contains(pair)
+    pub fn undelete(&mut self, source: &Path) {
+        self.deleted_entries.remove(source);
     }
 
-    pub fn undelete(&mut self, pair: &PathPair) {
-        self.deleted_entries.remove(pair);
+    /// The entries of the snapshot together with the last entries of deleted files,
+    /// whose content is kept in the archives until they are pruned
+    pub fn retained_entries(&self) -> impl Iterator<Item = &FileEntry> {
+        self.entries.values().chain(self.deleted_entries.values().map(|deleted| &deleted.entry))
     }
 
     /// returns the mtime entry matching key
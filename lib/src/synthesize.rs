@@ -0,0 +1,280 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::hash_map::Entry;
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::logging::Trap;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::traits::JsonFile;
+use crate::record::{Record, BackupKind};
+use crate::snapshot::{FileEntry, EntryKind};
+use crate::archive::{ArchiveWriter, Codec, archive_path, open_archive};
+use crate::chunks::{ChunkStore, chunk_root};
+use crate::utils::{get_datetime, source_subtree, lock_host};
+use crate::crypto;
+
+/// Synthesizes a full backup of a host from its record, without contacting the host.
+///
+/// Every file of the current record is copied from the archive of the snapshot it was
+/// last backed up in into the archive of a new snapshot, and the entries are rewritten
+/// to point only at it. The chain of earlier snapshots is no longer needed for restoring,
+/// and they can be pruned independently. Returns the name of the new snapshot.
+pub fn synthesize_full(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<String, Trap> {
+
+    // Never falling back to plaintext when encryption is configured
+    if global_config.encryption.is_some() && crypto::installed().is_none() {
+        return Err(Trap::Crypto(String::from("Encryption is configured, but the repository key is not unlocked")));
+    }
+
+    // $HOME/destination/$identifier
+    let host_root_path = global_config.backups.join(&host_config.identifier);
+    let record_dir_path = host_root_path.join(".records");
+
+    // Not alongside a backup of the host, which would record a snapshot on top of the old chain
+    let _host_lock = lock_host(&host_root_path)?;
+
+    let mut record = Record::deserialize_json(&record_dir_path.join("record.json"))
+        .map_err(|err| Trap::Deserialize(format!("Could not deserialize record: {}", err)))?;
+
+    if record.snapshot.entries.is_empty() {
+        return Err(Trap::Missing(format!("No snapshots of `{}` to synthesize from", host_config.identifier)));
+    }
+
+    let datetime = get_datetime();
+    let snapshot_root_path = host_root_path.join(&datetime);
+    let codec = host_config.compression.unwrap_or_default();
+
+    if record_dir_path.join(format!("{}.json", datetime)).exists() || archive_path(&snapshot_root_path, codec).exists() {
+        return Err(Trap::FS(format!("Snapshot {} exists already", datetime)));
+    }
+
+    consolidate(&mut record, &snapshot_root_path, codec, host_config.compression_level, host_config.store_compressed_media.unwrap_or(false))?;
+
+    // Counts as a full backup, the incrementals are on top of it from now on
+    record.last_full = Some(datetime.clone());
+    record.incrementals = 0;
+    record.size = record.snapshot.entries.values().map(|entry| entry.size).sum();
+
+    // Serializing records, the new snapshot being the latest
+    record.serialize_json(&record_dir_path.join("record.json"))
+        .map_err(|err| Trap::Serialize(format!("Could not serialize record: {}", err)))?;
+    record.serialize_json(&record_dir_path.join(format!("{}.json", datetime)))
+        .map_err(|err| Trap::Serialize(format!("Could not serialize record: {}", err)))?;
+
+    // Like every dated record, the new one references the chunks of its entries and deleted entries
+    let chunks: Vec<&String> = record.snapshot.retained_entries()
+        .filter_map(|entry| entry.chunks.as_ref())
+        .flatten()
+        .collect();
+
+    if !chunks.is_empty() {
+        ChunkStore::open(&chunk_root(global_config))?.add_refs(chunks.into_iter())?;
+    }
+
+    Ok(datetime)
+}
+
+/// Copies the files of record into the archives of snapshot_root_path, and rewrites the entries
+/// of record to be in it. Files in the chunk store stay where they are.
+fn consolidate(record: &mut Record, snapshot_root_path: &Path, codec: Codec, level: Option<i32>, store_compressed_media: bool) -> Result<(), Trap> {
+
+    // Media stays in the uncompressed archive if the host stores it as it is
+    let target_codec = |entry: &FileEntry| match entry.codec {
+        Codec::None if store_compressed_media => Codec::None,
+        _ => codec,
+    };
+
+    // (snapshot, member): source, of the files to copy
+    let mut wanted: FxHashMap<(PathBuf, PathBuf), PathBuf> = FxHashMap::default();
+    let mut archives: FxHashSet<(PathBuf, Codec)> = FxHashSet::default();
+
+    // Directories of the entries, the only ones to keep from the archives
+    let mut live_dirs: FxHashSet<PathBuf> = FxHashSet::default();
+
+    for (source, entry) in &record.snapshot.entries {
+        let member = source_subtree(Path::new(""), source);
+        live_dirs.extend(member.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()).map(Path::to_path_buf));
+
+        if entry.is_file() && entry.chunks.is_none() {
+            let recorded_member = entry.file_path.strip_prefix(&entry.snapshot_path).map_err(|err| {
+                Trap::Missing(format!("{:?} is not within its snapshot: {}", entry.file_path, err))
+            })?;
+
+            wanted.insert((entry.snapshot_path.clone(), recorded_member.to_path_buf()), source.clone());
+            archives.insert((entry.snapshot_path.clone(), entry.codec));
+        }
+    }
+
+    let mut writers: FxHashMap<Codec, ArchiveWriter> = FxHashMap::default();
+    writer(&mut writers, snapshot_root_path, codec, level)?;
+
+    // Newest first, so directories get their latest metadata
+    let mut archives: Vec<(PathBuf, Codec)> = archives.into_iter().collect();
+    archives.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut appended_dirs: FxHashSet<PathBuf> = FxHashSet::default();
+    for (snapshot_path, archive_codec) in archives {
+        let path = archive_path(&snapshot_path, archive_codec);
+        if !path.exists() {
+            continue; // snapshots from before the archives were streamed are directories
+        }
+
+        let copy_err = |err: io::Error| Trap::FS(format!("Could not copy from archive {:?}: {}", path, err));
+        let last = last_occurrences(&path, archive_codec).map_err(copy_err)?;
+        let mut archive = open_archive(&path, archive_codec).map_err(copy_err)?;
+
+        for (position, member) in archive.entries().map_err(copy_err)?.enumerate() {
+            let member = member.map_err(copy_err)?;
+            let member_path = member.path().map_err(copy_err)?.to_path_buf();
+
+            if member.header().entry_type().is_dir() {
+                if live_dirs.contains(&member_path) && appended_dirs.insert(member_path.clone()) {
+                    let mode = member.header().mode().unwrap_or(0o755);
+                    let mtime = member.header().mtime().unwrap_or(0);
+                    writer(&mut writers, snapshot_root_path, codec, level)?.append_dir(&member_path.to_string_lossy(), mode, mtime).map_err(copy_err)?;
+                }
+                continue;
+            }
+
+            // A member appended again, after a failed read, supersedes the earlier one
+            if last.get(&member_path) != Some(&position) {
+                continue;
+            }
+
+            let source = match wanted.remove(&(snapshot_path.clone(), member_path)) {
+                Some(source) => source,
+                None => continue, // since changed or deleted
+            };
+
+            let entry = &record.snapshot.entries[&source];
+            let name = source_subtree(Path::new(""), &source).to_string_lossy().into_owned();
+            let size = member.size();
+            writer(&mut writers, snapshot_root_path, target_codec(entry), level)?.append_file(&name, entry, size, member).map_err(copy_err)?;
+        }
+    }
+
+    // Files of snapshots which are still uncompressed directories
+    for ((_, _), source) in wanted {
+        let entry = &record.snapshot.entries[&source];
+        let file = fs::File::open(&entry.file_path).map_err(|err| {
+            Trap::Missing(format!("{:?} was not found in its snapshot {:?}: {}", source, entry.snapshot_path, err))
+        })?;
+
+        let size = file.metadata().map_err(|err| Trap::Metadata(format!("Could not get metadata of {:?}: {}", entry.file_path, err)))?.len();
+        let name = source_subtree(Path::new(""), &source).to_string_lossy().into_owned();
+        writer(&mut writers, snapshot_root_path, target_codec(entry), level)?.append_file(&name, entry, size, file).map_err(|err| {
+            Trap::FS(format!("Could not add {:?} to archive: {}", entry.file_path, err))
+        })?;
+    }
+
+    // Links and special files, hard links only to files which are in the main archive
+    let entries = &record.snapshot.entries;
+    let special_entries: Vec<(&PathBuf, &FileEntry)> = entries.iter()
+        .filter(|(_, entry)| !entry.is_file())
+        .filter(|(_, entry)| {
+            entry.kind != EntryKind::HardLink || entry.link_target.as_ref()
+                .and_then(|target| entries.get(target))
+                .map(|target| target.is_file() && target.chunks.is_none() && target_codec(target) == codec)
+                .unwrap_or(false)
+        })
+        .collect();
+
+    writer(&mut writers, snapshot_root_path, codec, level)?.append_special_entries(&special_entries).map_err(|err| {
+        Trap::FS(format!("Could not add links and special files to archive: {}", err))
+    })?;
+
+    for (codec, archive) in writers {
+        archive.finish().map_err(|err| {
+            Trap::FS(format!("Could not finish archive {:?}: {}", archive_path(snapshot_root_path, codec), err))
+        })?;
+    }
+
+    // Pointing every entry at the new snapshot
+    for (source, entry) in record.snapshot.entries.iter_mut() {
+        entry.codec = match entry.chunks {
+            Some(_) => entry.codec,
+            None if entry.is_file() => target_codec(entry),
+            None => codec,
+        };
+        entry.file_path = source_subtree(snapshot_root_path, source);
+        entry.snapshot_path = snapshot_root_path.to_path_buf();
+    }
+
+    record.codec = codec;
+    record.kind = Some(BackupKind::Synthetic);
+    Ok(())
+}
+
+/// The position of the last occurrence of every member in the archive at path
+fn last_occurrences(path: &Path, codec: Codec) -> io::Result<FxHashMap<PathBuf, usize>> {
+    let mut last = FxHashMap::default();
+    for (position, member) in open_archive(path, codec)?.entries()?.enumerate() {
+        last.insert(member?.path()?.to_path_buf(), position);
+    }
+
+    Ok(last)
+}
+
+/// The archive of snapshot_root_path with codec, created on first use
+fn writer<'a>(writers: &'a mut FxHashMap<Codec, ArchiveWriter>, snapshot_root_path: &Path, codec: Codec, level: Option<i32>) -> Result<&'a mut ArchiveWriter, Trap> {
+    if let Entry::Vacant(vacant) = writers.entry(codec) {
+        let path = archive_path(snapshot_root_path, codec);
+        let archive = ArchiveWriter::create(&path, codec, level).map_err(|err| {
+            Trap::FS(format!("Could not create archive {:?}: {}", path, err))
+        })?;
+        vacant.insert(archive);
+    }
+
+    Ok(writers.get_mut(&codec).unwrap())
+}
+
+#[test]
+fn test_consolidate() {
+    use std::io::Read;
+
+    let dir = std::env::temp_dir().join(format!("rensen-test-synthesize-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // Full backup of two files, one of them appended again after a failed read,
+    // then an incremental one where only one of them changed
+    let full = dir.join("2026-10-01-00-00-00");
+    let inc = dir.join("2026-10-02-00-00-00");
+
+    let mut record = Record::new();
+    for (snapshot, files) in [(&full, vec![("/etc/a", "a1"), ("/etc/b", "b0"), ("/etc/b", "b1")]), (&inc, vec![("/etc/a", "a2")])] {
+        let mut archive = ArchiveWriter::create(&archive_path(snapshot, Codec::Gzip), Codec::Gzip, None).unwrap();
+        archive.append_dir("etc", 0o755, 0).unwrap();
+
+        for (source, contents) in files {
+            let entry = FileEntry::from(source_subtree(snapshot, Path::new(source)), snapshot.clone(), 0, contents.len() as u64);
+            archive.append_file(&source[1..], &entry, entry.size, contents.as_bytes()).unwrap();
+            record.snapshot.entries.insert(PathBuf::from(source), entry);
+        }
+        archive.finish().unwrap();
+    }
+
+    let synthetic = dir.join("2026-10-03-00-00-00");
+    consolidate(&mut record, &synthetic, Codec::Zstd, None, false).unwrap();
+
+    assert!(record.snapshot.entries.values().all(|entry| entry.snapshot_path == synthetic && entry.codec == Codec::Zstd));
+    assert_eq!(record.codec, Codec::Zstd);
+
+    // Self-contained, with the latest version of every file
+    let mut contents: Vec<(String, String)> = open_archive(&archive_path(&synthetic, Codec::Zstd), Codec::Zstd).unwrap()
+        .entries().unwrap()
+        .map(|member| member.unwrap())
+        .filter(|member| member.header().entry_type().is_file())
+        .map(|mut member| {
+            let mut data = String::new();
+            member.read_to_string(&mut data).unwrap();
+            (member.path().unwrap().to_string_lossy().into_owned(), data)
+        })
+        .collect();
+    contents.sort();
+
+    assert_eq!(contents, vec![(String::from("etc/a"), String::from("a2")), (String::from("etc/b"), String::from("b1"))]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
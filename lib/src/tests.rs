@@ -34,7 +34,7 @@ pub fn test_serialize_yaml() {
         String::from("1.1.1.1"),
         22,
         Path::new("~/.ssh/testkey").to_path_buf(),
-        Path::new("remote/path").to_path_buf(),
+        vec![Path::new("remote/path").to_path_buf()],
         Path::new("dest/path").to_path_buf(),
         String::from("* 0 0 * * * *")
     );
@@ -44,7 +44,7 @@ pub fn test_serialize_yaml() {
         String::from("1.1.1.1"),
         22,
         Path::new("~/.ssh/testkey").to_path_buf(),
-        Path::new("remote/path").to_path_buf(),
+        vec![Path::new("remote/path").to_path_buf()],
         Path::new("dest/path").to_path_buf(),
         String::from("* 0 0 * * * *")
     );
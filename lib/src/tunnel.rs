@@ -0,0 +1,142 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use ssh2::{Session, Channel};
+
+use crate::logging::Trap;
+
+/// Forwards a local port to host:port through a `direct-tcpip` channel of sess (a jump host).
+/// libssh2 sessions can only run over a TcpStream, so the next session in the chain connects
+/// to the returned local address. Only the first connection is accepted, the forwarding thread
+/// owns sess and lives as long as the tunneled connection.
+pub fn forward(sess: Session, host: &str, port: u16) -> Result<SocketAddr, Trap> {
+    let listener = TcpListener::bind("127.0.0.1:0").map_err(|err| {
+        Trap::Connect(format!("Could not bind local port for tunnel: {}", err))
+    })?;
+
+    let local_addr = listener.local_addr().map_err(|err| {
+        Trap::Connect(format!("Could not get local address of tunnel: {}", err))
+    })?;
+
+    let host = host.to_string();
+    thread::spawn(move || {
+        if let Err(err) = pump(&sess, listener, &host, port) {
+            println!("Tunnel to {}:{} closed: {:?}", host, port, err);
+        }
+    });
+
+    Ok(local_addr)
+}
+
+/// Accepts the tunneled connection and copies data both ways between it and
+/// the channel, until either side is closed.
+fn pump(sess: &Session, listener: TcpListener, host: &str, port: u16) -> Result<(), Trap> {
+    let (mut local, _) = listener.accept().map_err(|err| {
+        Trap::Connect(format!("Could not accept tunneled connection: {}", err))
+    })?;
+    drop(listener);
+
+    let mut channel = sess.channel_direct_tcpip(host, port, None).map_err(|err| {
+        Trap::Channel(format!("Could not open direct-tcpip channel to {}:{}: {}", host, port, err))
+    })?;
+
+    // Both directions are served from this thread
+    sess.set_blocking(false);
+    local.set_nonblocking(true).map_err(|err| {
+        Trap::Connect(format!("Could not set tunneled connection non-blocking: {}", err))
+    })?;
+
+    let mut buffer = vec![0; 32 * 1024];
+    loop {
+        let mut idle = true;
+
+        if forward_chunk(&mut local, &mut channel, &mut buffer, &mut idle)? {
+            break;
+        }
+
+        if forward_chunk(&mut channel, &mut local, &mut buffer, &mut idle)? || channel.eof() {
+            break;
+        }
+
+        if idle {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    let _ = close(&mut channel, &mut local);
+    Ok(())
+}
+
+/// Copies what is available from reader to writer. Returns true if reader is closed.
+fn forward_chunk<R: Read, W: Write>(reader: &mut R, writer: &mut W, buffer: &mut [u8], idle: &mut bool) -> Result<bool, Trap> {
+    match reader.read(buffer) {
+        Ok(0) => Ok(true),
+        Ok(n) => {
+            *idle = false;
+            write_all(writer, &buffer[..n]).map_err(|err| {
+                Trap::Channel(format!("Could not write to tunnel: {}", err))
+            })?;
+            Ok(false)
+        },
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(Trap::Channel(format!("Could not read from tunnel: {}", err))),
+    }
+}
+
+/// Like Write::write_all, but waits for non-blocking writers instead of failing
+fn write_all<W: Write>(writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "tunnel closed")),
+            Ok(n) => data = &data[n..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+fn close(channel: &mut Channel, local: &mut TcpStream) -> io::Result<()> {
+    let _ = local.shutdown(std::net::Shutdown::Both);
+    channel.close().map_err(io::Error::from)
+}
+
+#[test]
+fn test_write_all() {
+    /// Accepts two bytes per write, blocking every other call
+    struct Throttled {
+        data: Vec<u8>,
+        blocked: bool,
+    }
+
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+
+            let n = buf.len().min(2);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut writer = Throttled { data: Vec::new(), blocked: false };
+    write_all(&mut writer, b"tunneled").unwrap();
+    assert_eq!(writer.data, b"tunneled");
+
+    let mut idle = true;
+    let mut buffer = [0; 4];
+    let mut output = Vec::new();
+    assert!(!forward_chunk(&mut &b"abc"[..], &mut output, &mut buffer, &mut idle).unwrap());
+    assert!(!idle);
+    assert!(forward_chunk(&mut &b""[..], &mut output, &mut buffer, &mut idle).unwrap());
+    assert_eq!(output, b"abc");
+}
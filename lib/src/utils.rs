@@ -1,11 +1,11 @@
 use std::fs::{self, File};
-use std::io::{self, SeekFrom, BufReader, BufWriter, Read};
+use std::collections::BTreeMap;
+use std::io::{self, SeekFrom, Read};
 use std::path::{Path, PathBuf}; use std::io::prelude::*;
-use flate2::{write::GzEncoder, read::GzDecoder};
-use flate2::Compression;
-use tar::{Builder, Archive};
+use tar::{Builder, Header, EntryType};
+use fxhash::FxHashMap;
 use sha3::{Digest, Sha3_256};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{self as unix_fs, PermissionsExt};
 use std::time::{SystemTime, Duration};
 use ssh2::FileStat;
 use chrono::offset;
@@ -14,6 +14,8 @@ use crate::logging;
 use logging::Trap;
 
 use crate::traits::ConvertFromPath;
+use crate::snapshot::{FileEntry, EntryKind};
+use crate::archive::{ArchiveWriter, Codec, open_archive};
 
 pub fn get_datetime() -> String {
     return offset::Local::now()
@@ -42,6 +44,10 @@ pub fn set_metadata(file: &mut File, stat: FileStat) -> Result<(), Trap> {
     // len/size
     let _ = file.set_len(stat.size.unwrap_or(0));
 
+    // Ownership, before permissions as chown clears setuid/setgid.
+    // Only succeeds when running as root.
+    let _ = unix_fs::fchown(&*file, stat.uid, stat.gid);
+
     // Permissions
     if let Some(raw_perms) = stat.perm {
         let _ = file.set_permissions(PermissionsExt::from_mode(raw_perms));
@@ -96,11 +102,15 @@ fn test_count_files() {
 }
 
 /// Archive directory with Tarball (tar::Builder) and
-/// compress with Gz (flate2::write::GzeEncoder, flate2::Compression).
+/// compress with codec at its default level (see ArchiveWriter).
+/// Encrypted with the repository key when it is installed (see crypto::install).
+/// The archive is written next to destination and moved into place when done.
 ///
 /// source: path for directory to compress
 /// destination: path to compressed and archived file
-pub fn make_tar_gz<SRC, DST>(source: SRC, destination: DST) -> io::Result<()>
+/// entries: recorded entries (by source path) of the files in source, giving their remote
+/// ownership, xattrs and ACL. Links and special files are added as headers only.
+pub fn make_tar_gz<SRC, DST>(source: SRC, destination: DST, entries: &[(&PathBuf, &FileEntry)], codec: Codec) -> io::Result<()>
 where 
     SRC: AsRef<Path>,
     DST: AsRef<Path>
@@ -112,35 +122,36 @@ where
     let file_count = count_files(source).unwrap();
     println!("Archiving: ({}/{})", 0, file_count);
 
-    // Temp tar file
-    let tar_file_path = "temp.tar";
-    let tar_file = File::create(tar_file_path)?;
+    // Files are looked up by their member name
+    let (files, special_entries): (Vec<_>, Vec<_>) = entries.iter()
+        .partition(|(_, entry)| entry.is_file());
+
+    let file_entries: FxHashMap<PathBuf, &FileEntry> = files.into_iter()
+        .map(|(source, entry)| (source_subtree(Path::new(""), source), entry))
+        .collect();
 
-    // Create a tarball
-    let mut tar_builder = Builder::new(tar_file);
-    add_dir_contents_to_tar(source, &mut tar_builder, source, &mut files_added, &file_count)?;
-    tar_builder.finish()?;
+    // Create a compressed tarball
+    let mut archive = ArchiveWriter::create(destination, codec, None)?;
+    add_dir_contents_to_tar(source, archive.builder(), source, &file_entries, &mut files_added, &file_count)?;
+    archive.append_special_entries(&special_entries)?;
 
     print!("Compressing... ");
-    // Gzip compress
-    let tar_file = File::open(tar_file_path)?;
-    let gz_file = File::create(destination)?;
-    let gz_encoder = GzEncoder::new(BufWriter::new(gz_file), Compression::default());
-    io::copy(&mut BufReader::new(tar_file), &mut BufWriter::new(gz_encoder))?;
+    archive.finish()?;
 
-    // Cleanup: remove temp tar file, remove uncompressed file
+    // Cleanup: remove uncompressed file
     let _ = fs::remove_dir_all(source);
-    let _ = fs::remove_file(tar_file_path);
     println!("Done");
 
     Ok(())
 }
 
 /// Recurses dir and adds it to the root tar_builder.
-fn add_dir_contents_to_tar(
+/// Files with a recorded entry get the ownership and extended attributes of the remote file.
+fn add_dir_contents_to_tar<W: Write>(
     root: &Path,
-    tar_builder: &mut Builder<File>,
+    tar_builder: &mut Builder<W>,
     dir: &Path,
+    file_entries: &FxHashMap<PathBuf, &FileEntry>,
     files_added: &mut i32,
     file_count: &usize
 ) -> io::Result<()> {
@@ -151,21 +162,306 @@ fn add_dir_contents_to_tar(
         let name = path.strip_prefix(root).unwrap().to_string_lossy().into_owned();
 
         if path.is_dir() {
-            tar_builder.append_dir(name, &path)?;
-            add_dir_contents_to_tar(root, tar_builder, &path, files_added, file_count)?;
+            tar_builder.append_dir(&name, &path)?;
+            add_dir_contents_to_tar(root, tar_builder, &path, file_entries, files_added, file_count)?;
         } else {
             *files_added += 1;
             clear_current_line();
             println!("Archiving: ({}/{})", files_added, file_count );
-            tar_builder.append_path_with_name(&path, name)?;
+
+            match file_entries.get(Path::new(&name)) {
+                Some(file_entry) => {
+                    let mut header = Header::new_gnu();
+                    header.set_metadata(&fs::metadata(&path)?);
+                    set_header_ownership(&mut header, file_entry)?;
+                    append_pax_extensions(tar_builder, &name, file_entry)?;
+                    tar_builder.append_data(&mut header, &name, File::open(&path)?)?;
+                },
+                None => tar_builder.append_path_with_name(&path, &name)?,
+            }
         }
     }
 
     Ok(())
 }
 
-// Decompresses and dearchives .tar.gz 
-pub fn demake_tar_gz<SRC, DST>(source: SRC, destination: DST) -> io::Result<()>
+/// Sets uid/gid and user/group names of header to those of the remote file, if recorded.
+pub fn set_header_ownership(header: &mut Header, entry: &FileEntry) -> io::Result<()> {
+    if let Some(uid) = entry.uid {
+        header.set_uid(uid as u64);
+    }
+
+    if let Some(gid) = entry.gid {
+        header.set_gid(gid as u64);
+    }
+
+    if let Some(owner) = &entry.owner {
+        header.set_username(owner)?;
+    }
+
+    if let Some(group) = &entry.group {
+        header.set_groupname(group)?;
+    }
+
+    Ok(())
+}
+
+/// Adds a PAX extended header for the following member (name), holding
+/// the xattrs (`SCHILY.xattr.*`) and ACL (`SCHILY.acl.access`) of entry.
+/// Does nothing if entry has neither.
+pub fn append_pax_extensions<W: Write>(tar_builder: &mut Builder<W>, name: &str, entry: &FileEntry) -> io::Result<()> {
+    let mut data = Vec::new();
+
+    for (key, value) in entry.xattrs.iter().flatten() {
+        let value = decode_hex(value).ok_or(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid value of xattr {}", key)
+        ))?;
+
+        data.extend(pax_record(&format!("SCHILY.xattr.{}", key), &value));
+    }
+
+    if let Some(acl) = &entry.acl {
+        data.extend(pax_record("SCHILY.acl.access", acl.as_bytes()));
+    }
+
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // The name of the extended header itself is not used when reading,
+    // keep it short to not need a long name entry of its own.
+    let file_name = Path::new(name).file_name().unwrap_or_default().to_string_lossy();
+    let pax_name: String = format!("PaxHeaders/{}", file_name).chars().take(99).collect();
+
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    tar_builder.append_data(&mut header, pax_name, &data[..])
+}
+
+/// Encodes a single PAX record: "<length> <key>=<value>\n",
+/// where length is the length of the whole record, including itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3; // ' ', '=' and '\n'
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+
+    let mut record = format!("{} {}=", length, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+#[test]
+fn test_pax_extensions() {
+    assert_eq!(pax_record("a", b"b"), b"6 a=b\n".to_vec());
+    assert_eq!(pax_record("SCHILY.xattr.user.test", b"abc").len(), 30);
+
+    let mut entry = FileEntry::new();
+    entry.xattrs = Some(BTreeMap::from([(String::from("user.test"), String::from("616263"))]));
+    entry.acl = Some(String::from("user::rw-,user:bob:r--,group::r--,mask::r--,other::r--"));
+
+    let mut tar_builder = Builder::new(Vec::new());
+    append_pax_extensions(&mut tar_builder, "srv/file", &entry).unwrap();
+    let mut header = Header::new_gnu();
+    header.set_size(0);
+    tar_builder.append_data(&mut header, "srv/file", io::empty()).unwrap();
+    let data = tar_builder.into_inner().unwrap();
+
+    let mut archive = tar::Archive::new(&data[..]);
+    let mut entries = archive.entries().unwrap();
+    let mut file = entries.next().unwrap().unwrap();
+    assert_eq!(file.path().unwrap(), Path::new("srv/file"));
+
+    let extensions: Vec<(String, Vec<u8>)> = file.pax_extensions().unwrap().unwrap()
+        .map(|extension| {
+            let extension = extension.unwrap();
+            (extension.key().unwrap().to_string(), extension.value_bytes().to_vec())
+        })
+        .collect();
+
+    assert_eq!(extensions[0], (String::from("SCHILY.xattr.user.test"), b"abc".to_vec()));
+    assert_eq!(extensions[1].0, "SCHILY.acl.access");
+    assert!(entries.next().is_none());
+}
+
+/// Decodes a hex string (as given by `getfattr -e hex`, with or without "0x")
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim_start_matches("0x");
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// User or group names by id
+pub type IdNames = FxHashMap<u32, String>;
+
+/// Parses the output of `getent passwd` or `getent group` into id: name
+pub fn parse_id_names(output: &str) -> IdNames {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse::<u32>().ok()?;
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+/// Parses the output of `getfattr -d -e hex` for a single file into name: value (hex)
+pub fn parse_getfattr(output: &str) -> BTreeMap<String, String> {
+    output.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.trim_start_matches("0x").to_string()),
+            None => (line.to_string(), String::new()),
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_ownership() {
+    let users = parse_id_names("root:x:0:0:root:/root:/bin/bash\nbob:x:1000:1000::/home/bob:/bin/sh\n");
+    assert_eq!(users.get(&0), Some(&String::from("root")));
+    assert_eq!(users.get(&1000), Some(&String::from("bob")));
+
+    let xattrs = parse_getfattr("# file: srv/file\nuser.test=0x616263\nuser.empty\n\n");
+    assert_eq!(xattrs.get("user.test"), Some(&String::from("616263")));
+    assert_eq!(xattrs.get("user.empty"), Some(&String::new()));
+    assert_eq!(decode_hex("0x616263"), Some(b"abc".to_vec()));
+    assert_eq!(decode_hex("6g"), None);
+}
+
+/// Adds links and special files to the tar_builder as headers.
+/// They are placed by their source path, like every other file in the archives,
+/// which also makes a hard link point to the member of the file it is linked to.
+pub fn append_special_entries<W: Write>(tar_builder: &mut Builder<W>, special_entries: &[(&PathBuf, &FileEntry)]) -> io::Result<()> {
+    for (source, entry) in special_entries {
+        let name = source_subtree(Path::new(""), source);
+
+        let mut header = Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(entry.mode & 0o7777);
+        header.set_mtime(entry.mtime);
+        set_header_ownership(&mut header, entry)?;
+        append_pax_extensions(tar_builder, &name.to_string_lossy(), entry)?;
+
+        match entry.kind {
+            EntryKind::Symlink | EntryKind::HardLink => {
+                let target = match &entry.link_target {
+                    Some(target) => target,
+                    None => continue,
+                };
+
+                if entry.kind == EntryKind::Symlink {
+                    header.set_entry_type(EntryType::Symlink);
+                    tar_builder.append_link(&mut header, &name, target)?;
+                } else {
+                    header.set_entry_type(EntryType::Link);
+                    tar_builder.append_link(&mut header, &name, source_subtree(Path::new(""), target))?;
+                }
+            },
+            EntryKind::Fifo | EntryKind::CharDevice | EntryKind::BlockDevice => {
+                header.set_entry_type(match entry.kind {
+                    EntryKind::Fifo => EntryType::Fifo,
+                    EntryKind::CharDevice => EntryType::Char,
+                    _ => EntryType::Block,
+                });
+
+                if let Some((major, minor)) = entry.device {
+                    header.set_device_major(major)?;
+                    header.set_device_minor(minor)?;
+                }
+
+                tar_builder.append_data(&mut header, &name, io::empty())?;
+            },
+            EntryKind::File => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_append_special_entries() {
+    let mut symlink = FileEntry::new();
+    symlink.kind = EntryKind::Symlink;
+    symlink.link_target = Some(PathBuf::from("../target"));
+
+    let mut hardlink = FileEntry::new();
+    hardlink.kind = EntryKind::HardLink;
+    hardlink.link_target = Some(PathBuf::from("/srv/file"));
+
+    let mut device = FileEntry::new();
+    device.kind = EntryKind::BlockDevice;
+    device.device = Some((8, 1));
+
+    let (symlink_path, hardlink_path, device_path) = (PathBuf::from("/srv/link"), PathBuf::from("/srv/hard"), PathBuf::from("/dev/sda1"));
+    let mut tar_builder = Builder::new(Vec::new());
+    append_special_entries(&mut tar_builder, &[(&symlink_path, &symlink), (&hardlink_path, &hardlink), (&device_path, &device)]).unwrap();
+    let data = tar_builder.into_inner().unwrap();
+
+    let mut archive = tar::Archive::new(&data[..]);
+    let headers: Vec<(PathBuf, EntryType, Option<PathBuf>)> = archive.entries().unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.path().unwrap().to_path_buf(), entry.header().entry_type(), entry.link_name().unwrap().map(|name| name.to_path_buf()))
+        })
+        .collect();
+
+    assert_eq!(headers[0], (PathBuf::from("srv/link"), EntryType::Symlink, Some(PathBuf::from("../target"))));
+    assert_eq!(headers[1], (PathBuf::from("srv/hard"), EntryType::Link, Some(PathBuf::from("srv/file"))));
+    assert_eq!(headers[2], (PathBuf::from("dev/sda1"), EntryType::Block, None));
+}
+
+/// Groups paths sharing the same inode (device:inode as key), mapping every path
+/// to the first (in sorted order) path of its group. The first path is not included.
+pub fn group_hardlinks(mut links: Vec<(String, PathBuf)>) -> FxHashMap<PathBuf, PathBuf> {
+    links.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut firsts: FxHashMap<String, PathBuf> = FxHashMap::default();
+    let mut hardlinks: FxHashMap<PathBuf, PathBuf> = FxHashMap::default();
+
+    for (inode, path) in links {
+        match firsts.get(&inode) {
+            Some(first) => {
+                hardlinks.insert(path, first.clone());
+            },
+            None => {
+                firsts.insert(inode, path);
+            },
+        }
+    }
+
+    hardlinks
+}
+
+#[test]
+fn test_group_hardlinks() {
+    let links = vec![
+        (String::from("2049:12"), PathBuf::from("/srv/b")),
+        (String::from("2049:12"), PathBuf::from("/srv/a")),
+        (String::from("2050:12"), PathBuf::from("/mnt/a")),
+        (String::from("2049:12"), PathBuf::from("/srv/c")),
+    ];
+
+    let hardlinks = group_hardlinks(links);
+    assert_eq!(hardlinks.len(), 2);
+    assert_eq!(hardlinks.get(Path::new("/srv/b")), Some(&PathBuf::from("/srv/a")));
+    assert_eq!(hardlinks.get(Path::new("/srv/c")), Some(&PathBuf::from("/srv/a")));
+    assert_eq!(hardlinks.get(Path::new("/mnt/a")), None);
+}
+
+// Decrypts (if encrypted), decompresses (with codec) and dearchives
+pub fn demake_tar_gz<SRC, DST>(source: SRC, destination: DST, codec: Codec) -> io::Result<()>
 where
     SRC: AsRef<Path>,
     DST: AsRef<Path>,
@@ -174,10 +470,7 @@ where
 
     let _ = fs::create_dir_all(destination);
 
-    let gz_file = fs::File::open(source)?;
-    let gz_decoder = GzDecoder::new(BufReader::new(gz_file));
-
-    let mut archive = Archive::new(gz_decoder);
+    let mut archive = open_archive(source.as_ref(), codec)?;
     archive.unpack(destination)?;
 
     Ok(())
@@ -243,6 +536,86 @@ pub fn replace_common_prefix(one: &PathBuf, two: &PathBuf, replacement: &PathBuf
     return new_path;
 }
 
+/// Returns where a remote path is placed beneath root, keeping
+/// the full remote path so that every source gets its own subtree.
+///
+/// # Example:
+///
+/// (root, /var/lib/app -> root/var/lib/app)
+///
+pub fn source_subtree(root: &Path, remote_path: &Path) -> PathBuf {
+    root.join(remote_path.strip_prefix("/").unwrap_or(remote_path))
+}
+
+#[test]
+fn test_source_subtree() {
+    let root = Path::new("/etc/rensen/backups/1.1.1.1/2024-05-15-08-10-30");
+    assert_eq!(source_subtree(root, Path::new("/var/lib/app")), root.join("var/lib/app"));
+    assert_eq!(source_subtree(root, Path::new("remote/path")), root.join("remote/path"));
+    assert_eq!(source_subtree(root, Path::new("/")), root.to_path_buf());
+}
+
+/// Delay before retry number attempt (from 0), doubling initial every attempt, at most 5 minutes
+pub fn backoff_delay(initial: Duration, attempt: u32) -> Duration {
+    initial.saturating_mul(2u32.saturating_pow(attempt))
+        .min(Duration::from_secs(300))
+}
+
+#[test]
+fn test_backoff_delay() {
+    let initial = Duration::from_secs(2);
+    assert_eq!(backoff_delay(initial, 0), Duration::from_secs(2));
+    assert_eq!(backoff_delay(initial, 1), Duration::from_secs(4));
+    assert_eq!(backoff_delay(initial, 3), Duration::from_secs(16));
+    assert_eq!(backoff_delay(initial, 40), Duration::from_secs(300));
+}
+
+/// Expands a leading `~` or `$HOME` of path to the home directory
+pub fn expand_home(path: &Path) -> PathBuf {
+    let home = match std::env::var("HOME") {
+        Ok(home) => PathBuf::from(home),
+        Err(_) => return path.to_path_buf(),
+    };
+
+    let mut components = path.components();
+    match components.next() {
+        Some(first) if first.as_os_str() == "~" || first.as_os_str() == "$HOME" => {
+            home.join(components.as_path())
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+#[test]
+fn test_expand_home() {
+    let home = PathBuf::from(std::env::var("HOME").unwrap());
+    assert_eq!(expand_home(Path::new("~/.ssh/ed25519")), home.join(".ssh/ed25519"));
+    assert_eq!(expand_home(Path::new("$HOME/.ssh/ed25519")), home.join(".ssh/ed25519"));
+    assert_eq!(expand_home(Path::new("/etc/rensen/key")), PathBuf::from("/etc/rensen/key"));
+    assert_eq!(expand_home(Path::new("keys/~")), PathBuf::from("keys/~"));
+}
+
+/// Takes an exclusive lock on the snapshots of the host at host_root_path, waiting for it if taken.
+/// It is released when the returned file is closed, so that backups of a host run one at a time,
+/// whichever process or task runs them.
+pub fn lock_host(host_root_path: &Path) -> Result<File, Trap> {
+    let lock_path = host_root_path.join(".rensen.lock");
+    File::options().create(true).truncate(false).write(true).open(&lock_path)
+        .and_then(|file| file.lock().map(|_| file))
+        .map_err(|err| Trap::FS(format!("Could not lock snapshots {:?}: {}", lock_path, err)))
+}
+
+/// Quotes path for use as an argument in a remote shell command
+pub fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote(Path::new("/home/user/my file")), "'/home/user/my file'");
+    assert_eq!(shell_quote(Path::new("/home/user/it's")), "'/home/user/it'\\''s'");
+}
+
 /// Wrapper for std::fs::copy which forces the write by
 /// creating missing directories
 pub fn force_copy(source: &PathBuf, destination: &PathBuf) -> io::Result<()> {
@@ -263,6 +636,22 @@ pub fn force_copy(source: &PathBuf, destination: &PathBuf) -> io::Result<()> {
     Ok(())
 }
 
+/// Reads until buffer is full or reader is at EOF,
+/// returns the number of bytes read.
+pub fn read_block<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match reader.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(total)
+}
+
 #[test]
 fn test_hash() {
     let path = Path::new("src/hosts");